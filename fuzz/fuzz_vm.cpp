@@ -0,0 +1,473 @@
+// Generates random programs that pass the Verifier and runs each one in a
+// child process. A program that crashes the VM, outlives the time limit,
+// halts with anything but the sentinel it pushed last, or is rejected by
+// the Verifier is written to fuzz-<seed>.bin so it can be replayed with
+// `flan fuzz-<seed>.bin`.
+//
+// Usage: flan-fuzz [iterations] [first seed]
+
+#include <fcntl.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+#include <algorithm>
+#include <csignal>
+#include <cstdint>
+#include <cstdlib>
+#include <cstring>
+#include <fstream>
+#include <iostream>
+#include <random>
+#include <sstream>
+#include <string>
+#include <vector>
+
+#include "flan.hpp"
+
+using namespace flan;
+
+// What the generated program leaves on an otherwise empty stack before
+// halting, so a miscounted stack shows up as a wrong result
+const std::int64_t SENTINEL = 0x5EED;
+const auto NAME_COUNT = 3;
+const auto MAX_NESTING = 3;
+const std::size_t MAX_INSTRUCTIONS = 100000;
+const unsigned TIME_LIMIT_SECONDS = 5;
+
+// Sanitizer reports would otherwise exit with the same status as a runtime
+// error in the program, which is not a failure
+extern "C" const char *__asan_default_options() {
+  return "abort_on_error=1";
+}
+
+extern "C" const char *__ubsan_default_options() {
+  return "halt_on_error=1:abort_on_error=1";
+}
+
+class Generator {
+ public:
+  explicit Generator(std::uint32_t seed) : rng{seed} {}
+
+  std::vector<std::uint8_t> program() {
+    this->out.clear();
+    for (auto byte : MAGIC_NUMBER) this->u8(byte);
+    for (auto part : VERSION) this->u8(part);
+    this->u8(0);
+
+    // A single error info entry, which every errInfo operand points at
+    this->u16(1);
+    this->u16(1);
+    this->u16(4);
+    for (auto c : std::string("fuzz")) this->u8(c);
+
+    this->u16(NAME_COUNT);
+    for (auto i = 0; i < NAME_COUNT; i++)
+      this->shortString("g" + std::to_string(i));
+
+    std::int64_t depth = 0;
+    this->block(depth, 0);
+    this->popTo(depth, 0);
+    this->op(InstructionType::Load);
+    this->u8(0);
+    this->u32(static_cast<std::uint32_t>(SENTINEL));
+    this->op(InstructionType::Halt);
+    return this->out;
+  }
+
+ private:
+  std::mt19937 rng;
+  std::vector<std::uint8_t> out;
+
+  std::uint32_t below(std::uint32_t n) {
+    return std::uniform_int_distribution<std::uint32_t>(0, n - 1)(this->rng);
+  }
+
+  void u8(std::uint8_t byte) {
+    this->out.push_back(byte);
+  }
+
+  void u16(std::uint16_t value) {
+    this->u8(value & 0xFF);
+    this->u8(value >> 8);
+  }
+
+  void u32(std::uint32_t value) {
+    for (auto i = 0; i < 4; i++) this->u8((value >> (i * 8)) & 0xFF);
+  }
+
+  void patch32(std::size_t at, std::uint32_t value) {
+    for (auto i = 0; i < 4; i++) this->out[at + i] = (value >> (i * 8)) & 0xFF;
+  }
+
+  void op(InstructionType type) {
+    this->u8(static_cast<std::uint8_t>(type));
+  }
+
+  void shortString(const std::string &str) {
+    this->u8(str.size());
+    for (auto c : str) this->u8(c);
+  }
+
+  std::string key() {
+    static const char *KEYS[] = {"a", "b", "length", "x"};
+    return KEYS[this->below(4)];
+  }
+
+  void popTo(std::int64_t &depth, std::int64_t target) {
+    while (depth > target) {
+      auto count = std::min<std::int64_t>(depth - target, UINT8_MAX);
+      this->op(InstructionType::PopN);
+      this->u8(count);
+      depth -= count;
+    }
+    while (depth < target) {
+      this->op(InstructionType::Load0);
+      depth++;
+    }
+  }
+
+  void value(int nesting) {
+    switch (this->below(nesting < MAX_NESTING ? 9 : 8)) {
+      case 0:
+        this->u8(0);
+        this->u32(this->rng());
+        break;
+      case 1: {
+        this->u8(1);
+        static const double FLOATS[] = {0.0, -0.0, 1.5, 1e308, -1e308};
+        auto f = FLOATS[this->below(5)];
+        std::uint8_t bytes[8];
+        std::memcpy(bytes, &f, 8);
+        for (auto byte : bytes) this->u8(byte);
+        break;
+      }
+      case 2:
+        this->u8(2);
+        this->u8(this->below(2));
+        break;
+      case 3:
+        this->u8(3);
+        break;
+      case 4: {
+        this->u8(4);
+        auto length = this->below(4);
+        this->u16(length);
+        for (std::uint32_t i = 0; i < length; i++)
+          this->u8('a' + this->below(3));
+        break;
+      }
+      case 5:
+        this->u8(5);
+        this->shortString(this->key());
+        break;
+      case 6:
+        this->u8(7);
+        this->u32(this->rng());
+        this->u32(this->rng());
+        break;
+      case 7: {
+        this->u8(8);
+        auto length = this->below(4);
+        this->u32(length);
+        for (std::uint32_t i = 0; i < length; i++)
+          this->u8('a' + this->below(3));
+        break;
+      }
+      // Last, so that it can be left out once functions nest too deeply
+      case 8: {
+        this->u8(6);
+        this->shortString("f");
+        auto arity = this->below(3);
+        this->u16(arity);
+        auto lengthAt = this->out.size();
+        this->u32(0);
+        auto start = this->out.size();
+        std::int64_t depth = arity + 1;
+        this->block(depth, nesting + 1);
+        if (depth == 0) {
+          this->op(InstructionType::Load0);
+          depth++;
+        }
+        this->op(InstructionType::RetFn);
+        this->patch32(lengthAt, this->out.size() - start);
+        this->op(InstructionType::EndFn);
+        break;
+      }
+    }
+  }
+
+  // Emits instructions that only pop what is there, leaving `depth` as the
+  // number of values on the stack afterwards
+  void block(std::int64_t &depth, int nesting) {
+    auto length = 1 + this->below(nesting == 0 ? 200 : 20);
+    for (std::uint32_t i = 0; i < length; i++)
+      this->instruction(depth, nesting);
+  }
+
+  void instruction(std::int64_t &depth, int nesting) {
+    auto slot = [&]() { return this->below(depth); };
+
+    switch (this->below(18)) {
+      case 0:
+        this->u8(this->below(7));  // LoadNeg1 to Load5
+        depth++;
+        return;
+      case 1:
+        this->op(InstructionType::Load);
+        this->value(nesting);
+        depth++;
+        return;
+      case 2: {
+        auto count = 1 + this->below(3);
+        this->op(InstructionType::Push);
+        this->u8(count);
+        for (std::uint32_t i = 0; i < count; i++) this->value(nesting);
+        depth += count;
+        return;
+      }
+      default:
+        break;
+    }
+
+    // Everything below pops at least one value
+    if (depth == 0) return this->instruction(depth, nesting);
+
+    switch (this->below(16)) {
+      case 0:
+        this->op(InstructionType::Pop);
+        depth--;
+        break;
+      case 1:
+        this->op(InstructionType::Dup);
+        depth++;
+        break;
+      case 2: {
+        static const InstructionType UNARY[] = {InstructionType::Not,
+                                                InstructionType::Negate};
+        this->op(UNARY[this->below(2)]);
+        break;
+      }
+      case 3: {
+        std::int64_t count = this->below(std::min<std::int64_t>(depth, 4) + 1);
+        this->op(this->below(2) ? InstructionType::InitList
+                                : InstructionType::InitTup);
+        this->u32(count);
+        depth += 1 - count;
+        break;
+      }
+      case 4: {
+        std::int64_t count = this->below(3);
+        this->op(InstructionType::DestructureList);
+        this->u16(0);
+        this->u32(count);
+        depth += count - 1;
+        break;
+      }
+      case 5: {
+        std::int64_t count = this->below(3);
+        this->op(InstructionType::DestructureObject);
+        this->u16(0);
+        this->u32(count);
+        for (std::uint32_t i = 0; i < count; i++)
+          this->shortString(this->key());
+        depth += count - 1;
+        break;
+      }
+      case 6:
+        this->op(InstructionType::GetMember);
+        this->u16(0);
+        this->shortString(this->key());
+        break;
+      case 7: {
+        auto get = this->below(2) == 0;
+        this->op(get ? InstructionType::GetLocal : InstructionType::SetLocal);
+        this->u16(slot());
+        if (get) depth++;
+        break;
+      }
+      case 8:
+        this->op(this->below(2) ? InstructionType::IncLocal
+                                : InstructionType::DecLocal);
+        this->u16(0);
+        this->u16(slot());
+        depth++;
+        break;
+      case 9: {
+        static const InstructionType GLOBALS[] = {
+            InstructionType::DefGlobal, InstructionType::SetGlobal,
+            InstructionType::OverrideGlobal, InstructionType::GetGlobal,
+            InstructionType::IncGlobal, InstructionType::DecGlobal};
+        // The first three pop the value they store
+        auto which = this->below(6);
+        this->op(GLOBALS[which]);
+        this->u16(0);
+        this->u16(this->below(NAME_COUNT));
+        depth += which < 3 ? -1 : 1;
+        break;
+      }
+      case 10: {
+        auto argCount = this->below(depth);
+        this->op(InstructionType::CallFn);
+        this->u16(0);
+        this->u16(argCount);
+        depth -= argCount;
+        break;
+      }
+      case 11:
+        // A conditional jump over code that ends at the depth it started at
+        if (nesting < MAX_NESTING) {
+          this->op(this->below(2) ? InstructionType::Jz : InstructionType::Jnz);
+          depth--;
+          this->jumpOver(depth, nesting);
+        }
+        break;
+      case 12:
+        // A short circuit, which leaves one value either way
+        if (nesting < MAX_NESTING) {
+          this->op(this->below(2) ? InstructionType::JzOrPop
+                                  : InstructionType::JnzOrPop);
+          depth--;
+          this->jumpOver(depth, nesting, 1);
+        }
+        break;
+      case 13:
+        if (nesting < MAX_NESTING) {
+          this->op(InstructionType::Jmp);
+          this->jumpOver(depth, nesting);
+        }
+        break;
+      default:
+        if (depth >= 2) this->binary(depth);
+        break;
+    }
+  }
+
+  void binary(std::int64_t &depth) {
+    static const InstructionType WITH_ERR_INFO[] = {
+        InstructionType::Add, InstructionType::Sub, InstructionType::Mul,
+        InstructionType::Div, InstructionType::Mod, InstructionType::Eq,
+        InstructionType::NEq, InstructionType::LT,  InstructionType::LTE,
+        InstructionType::GT,  InstructionType::GTE, InstructionType::GetIndex};
+
+    switch (this->below(5)) {
+      case 0:
+        this->op(this->below(2) ? InstructionType::And : InstructionType::Or);
+        depth--;
+        break;
+      case 1: {
+        auto nip = this->below(2) == 0;
+        this->op(nip ? InstructionType::Nip : InstructionType::Dup2);
+        depth += nip ? -1 : 2;
+        break;
+      }
+      case 2:
+        this->op(InstructionType::SetMember);
+        this->u16(0);
+        this->shortString(this->key());
+        depth -= 2;
+        break;
+      case 3:
+        if (depth >= 3) {
+          this->op(InstructionType::SetIndex);
+          this->u16(0);
+          depth -= 3;
+        }
+        break;
+      default:
+        this->op(WITH_ERR_INFO[this->below(12)]);
+        this->u16(0);
+        depth--;
+        break;
+    }
+  }
+
+  // Ends the skipped code at the depth it started at, plus what the jump
+  // keeps when taken
+  void jumpOver(std::int64_t &depth, int nesting, std::int64_t kept = 0) {
+    auto offsetAt = this->out.size();
+    this->u32(0);
+    auto entry = depth;
+    this->block(depth, nesting + 1);
+    this->popTo(depth, entry + kept);
+    this->patch32(offsetAt, this->out.size() - offsetAt - 4);
+  }
+};
+
+static void save(const std::vector<std::uint8_t> &program, std::uint32_t seed) {
+  auto path = "fuzz-" + std::to_string(seed) + ".bin";
+  std::ofstream file(path, std::ios::binary);
+  file.write(reinterpret_cast<const char *>(program.data()), program.size());
+  std::cerr << "seed " << seed << ": saved to " << path << "\n";
+}
+
+// Runs the program in a child, so that an error exiting the process or a
+// crash does not end the fuzzing run
+static std::string run(const std::vector<std::uint8_t> &program) {
+  auto pid = fork();
+  if (pid == 0) {
+    auto devNull = open("/dev/null", O_WRONLY);
+    dup2(devNull, STDOUT_FILENO);
+    dup2(devNull, STDERR_FILENO);
+    alarm(TIME_LIMIT_SECONDS);
+
+    VMOptions options;
+    options.maxInstructions = MAX_INSTRUCTIONS;
+    std::istringstream input(
+        std::string(program.begin(), program.end()), std::ios::binary);
+    VM vm(input, "<fuzz>", options);
+    Value result;
+    try {
+      result = vm.run();
+    } catch (const RuntimeError &) {
+      std::exit(EXIT_RUNTIME_ERROR);
+    }
+    if (!std::holds_alternative<std::int64_t>(result.value) ||
+        std::get<std::int64_t>(result.value) != SENTINEL)
+      std::abort();
+    std::exit(EXIT_SUCCESS);
+  }
+
+  int status;
+  waitpid(pid, &status, 0);
+  if (WIFSIGNALED(status)) {
+    if (WTERMSIG(status) == SIGALRM) return "timed out";
+    if (WTERMSIG(status) == SIGABRT)
+      return "aborted or halted with a wrong stack";
+    return std::string("killed by ") + strsignal(WTERMSIG(status));
+  }
+  if (WEXITSTATUS(status) == EXIT_LOAD_ERROR) return "failed to load";
+  return "";
+}
+
+int main(int argc, char **argv) {
+  unsigned long iterations =
+      argc > 1 ? std::strtoul(argv[1], nullptr, 10) : 1000;
+  std::uint32_t firstSeed =
+      argc > 2 ? std::strtoul(argv[2], nullptr, 10) : std::random_device{}();
+
+  std::size_t failures = 0;
+  for (unsigned long i = 0; i < iterations; i++) {
+    std::uint32_t seed = firstSeed + i;
+    auto program = Generator(seed).program();
+
+    // The generator is meant to only produce valid programs, so a rejection
+    // is a bug in either one of them
+    auto copy = program;
+    Verifier verifier(copy.data(), copy.size());
+    std::string failure;
+    if (!verifier.verify())
+      failure = "rejected by the verifier: " + verifier.error;
+    else
+      failure = run(program);
+
+    if (!failure.empty()) {
+      std::cerr << "seed " << seed << ": " << failure << "\n";
+      save(program, seed);
+      failures++;
+    }
+  }
+
+  std::cout << iterations << " programs from seed " << firstSeed << ", "
+            << failures << " failures\n";
+  return failures == 0 ? EXIT_SUCCESS : EXIT_FAILURE;
+}
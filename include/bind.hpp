@@ -0,0 +1,147 @@
+#pragma once
+
+#include <cstdint>
+#include <string>
+#include <tuple>
+#include <type_traits>
+#include <typeinfo>
+#include <utility>
+#include <variant>
+#include <vector>
+
+#include "gc.hpp"
+#include "vm.hpp"
+
+namespace flan {
+
+// Reports a parameter that was given a value of the wrong type. `position`
+// counts from 1.
+[[noreturn]] inline void bindArgError(VM &vm,
+                                      std::size_t position,
+                                      const char *expected,
+                                      Value value) {
+  vm.nativeError("Expected " + std::string(expected) + " as argument " +
+                 std::to_string(position) + " but got " +
+                 value.toDbgString());
+}
+
+// The parameter types a bound function may take. An integer is accepted
+// where a float is expected, as arithmetic would widen it.
+template <typename T>
+T bindFromValue(VM &vm, Value value, std::size_t position) {
+  if constexpr (std::is_same_v<T, Value>) {
+    return value;
+  } else if constexpr (std::is_same_v<T, std::int64_t>) {
+    if (!std::holds_alternative<std::int64_t>(value.value))
+      bindArgError(vm, position, "an integer", value);
+    return std::get<std::int64_t>(value.value);
+  } else if constexpr (std::is_same_v<T, double>) {
+    if (std::holds_alternative<std::int64_t>(value.value))
+      return static_cast<double>(std::get<std::int64_t>(value.value));
+    if (!std::holds_alternative<double>(value.value))
+      bindArgError(vm, position, "a float", value);
+    return std::get<double>(value.value);
+  } else if constexpr (std::is_same_v<T, bool>) {
+    if (!std::holds_alternative<bool>(value.value))
+      bindArgError(vm, position, "a bool", value);
+    return std::get<bool>(value.value);
+  } else if constexpr (std::is_same_v<T, std::string>) {
+    if (!std::holds_alternative<Object *>(value.value) ||
+        typeid(*std::get<Object *>(value.value)) != typeid(String))
+      bindArgError(vm, position, "a string", value);
+    return static_cast<String *>(std::get<Object *>(value.value))->value;
+  } else {
+    static_assert(sizeof(T) == 0,
+                  "bound parameters must be std::int64_t, double, bool, "
+                  "std::string or Value");
+  }
+}
+
+template <typename T>
+Value bindToValue(VM &vm, T result) {
+  if constexpr (std::is_same_v<T, Value>) {
+    return result;
+  } else if constexpr (std::is_same_v<T, std::string>) {
+    return vm.getGC().createString(result);
+  } else if constexpr (std::is_same_v<T, bool>) {
+    return Value(result);
+  } else if constexpr (std::is_same_v<T, std::int64_t>) {
+    return Value(result);
+  } else if constexpr (std::is_same_v<T, double>) {
+    return Value(result);
+  } else {
+    static_assert(sizeof(T) == 0,
+                  "bound functions must return void, std::int64_t, double, "
+                  "bool, std::string or Value");
+  }
+}
+
+// Splits a function pointer type into its result and the parameters that
+// come from Flan, leaving out a leading `VM &`
+template <typename Fn>
+struct BindSignature;
+
+template <typename R, typename... Params>
+struct BindSignature<R (*)(Params...)> {
+  using Result = R;
+  using Args = std::tuple<std::decay_t<Params>...>;
+  static const bool takesVM = false;
+};
+
+// Functions that take the VM first can report their own errors with
+// vm.nativeError, or allocate through vm.getGC()
+template <typename R, typename... Params>
+struct BindSignature<R (*)(VM &, Params...)> {
+  using Result = R;
+  using Args = std::tuple<std::decay_t<Params>...>;
+  static const bool takesVM = true;
+};
+
+template <auto Fn>
+struct Binding {
+  using Signature = BindSignature<decltype(Fn)>;
+  using Args = typename Signature::Args;
+  static const std::uint16_t arity = std::tuple_size_v<Args>;
+
+  static Value call(VM &vm, std::vector<Value> &args) {
+    return invoke(vm, args, std::make_index_sequence<arity>());
+  }
+
+  // Converts the arguments in order, so the first bad one is the one
+  // reported
+  template <std::size_t... I>
+  static Value invoke(VM &vm,
+                      std::vector<Value> &args,
+                      std::index_sequence<I...>) {
+    Args converted{
+        bindFromValue<std::tuple_element_t<I, Args>>(vm, args[I], I + 1)...};
+    auto callFn = [&](auto &...params) {
+      if constexpr (Signature::takesVM)
+        return Fn(vm, params...);
+      else
+        return Fn(params...);
+    };
+
+    if constexpr (std::is_void_v<typename Signature::Result>) {
+      std::apply(callFn, converted);
+      return Value();
+    } else {
+      return bindToValue<typename Signature::Result>(
+          vm, std::apply(callFn, converted));
+    }
+  }
+};
+
+// Makes a native function out of a plain C++ function, taking its arity from
+// the signature and converting arguments and the result, e.g.
+//
+//   static double scale(std::int64_t n, double by) { return n * by; }
+//   vm.defineGlobal("scale", bindNative<scale>(vm.getGC(), "scale"));
+//
+// A parameter of the wrong type is reported as a runtime error naming its
+// position. A function may also take `VM &` as its first parameter.
+template <auto Fn>
+Value bindNative(GC &gc, std::string name) {
+  return gc.createNativeFunction(name, Binding<Fn>::arity, Binding<Fn>::call);
+}
+}  // namespace flan
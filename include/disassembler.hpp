@@ -0,0 +1,43 @@
+#pragma once
+
+#include <cstdint>
+#include <ostream>
+#include <string>
+#include <vector>
+
+#include "vm.hpp"
+
+namespace flan {
+
+// Prints bytecode as one instruction per line, with byte offsets and
+// decoded operands. Function bodies are printed indented under the
+// instruction that loads them. The input must already have passed the
+// Verifier.
+class Disassembler {
+ public:
+  Disassembler(std::uint8_t *buffer, std::size_t size, std::ostream &out);
+  void disassemble();
+
+ private:
+  std::uint8_t *buffer;
+  std::size_t size;
+  std::ostream &out;
+  std::size_t pos = 0;
+  std::vector<std::string> names;
+
+  std::uint8_t readUInt8();
+  std::uint16_t readUInt16();
+  std::uint32_t readUInt32();
+  std::string readShortString();
+
+  void header();
+  void code(std::size_t end, int indent);
+  // Function bodies found while decoding operands, printed after the line
+  struct Body {
+    std::size_t start;
+    std::size_t end;
+  };
+  std::string value(std::vector<Body> &bodies);
+  std::string name();
+};
+}  // namespace flan
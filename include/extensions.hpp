@@ -0,0 +1,23 @@
+#pragma once
+
+#include <cstdint>
+#include <vector>
+
+#include "gc.hpp"
+
+namespace flan {
+
+// An instruction outside the stable opcode set, encoded as
+// `Ext <u8 id> <u16 errInfoIdx>`. It pops `operands` values, passed in the
+// order they were pushed, and pushes what `fn` returns. Experiments live
+// here so that trying one out never renumbers the stable opcodes or breaks
+// bytecode that was compiled without it.
+struct Extension {
+  const char *name;  // namespaced, e.g. "list.sum"
+  std::uint8_t operands;
+  Value (*fn)(VM &vm, std::vector<Value> &operands);
+};
+
+// Returns the extension registered under `id` in this build, or nullptr
+const Extension *findExtension(std::uint8_t id);
+}  // namespace flan
@@ -1 +1,26 @@
+#pragma once
 
+// The public API of the Flan library, for programs that embed the runtime
+// instead of shelling out to the flan executable. Anything reachable from
+// here is kept source compatible within a minor release:
+//
+//   VM, VMOptions     load, verify and run a bytecode file
+//   Value, GC         values passed to and returned from the VM
+//   OrderedMap        the key-value storage of tables
+//   defineNatives     the standard modules every VM starts with
+//   bindNative        makes a native out of a typed C++ function
+//   SymbolTable       the ids global names are stored under
+//   Verifier          checks bytecode without running it
+//   Disassembler      prints bytecode as readable instructions
+//   Plugin            what a library loaded by import_native exports
+//
+// Other headers in include/ are internal and may change at any time.
+
+#include "bind.hpp"
+#include "disassembler.hpp"
+#include "gc.hpp"
+#include "natives.hpp"
+#include "plugin.hpp"
+#include "symbols.hpp"
+#include "verifier.hpp"
+#include "vm.hpp"
@@ -1,8 +1,10 @@
 #pragma once
 #include <cstdint>
 #include <forward_list>
+#include <initializer_list>
 #include <string>
 #include <unordered_map>
+#include <utility>
 #include <variant>
 #include <vector>
 
@@ -10,6 +12,7 @@
 
 namespace flan {
 
+class VM;
 struct Object;
 
 struct Value {
@@ -23,7 +26,41 @@ struct Value {
 
   std::string toString();
   std::string toDbgString();
+  // _, false, 0 and 0.0 are falsy; everything else, including empty strings
+  // and collections, is truthy. Not, Jz, Jnz, And, Or and bool() all go
+  // through this.
   bool truthy();
+  void mark();
+};
+
+// String-keyed map that iterates in insertion order, so a table prints and
+// iterates in the order its literal was written. Erasing is linear.
+class OrderedMap {
+ public:
+  using Entry = std::pair<std::string, Value>;
+  using iterator = std::vector<Entry>::iterator;
+  using const_iterator = std::vector<Entry>::const_iterator;
+
+  OrderedMap() = default;
+  OrderedMap(std::initializer_list<Entry> entries);
+
+  Value& operator[](const std::string& key);
+  std::size_t count(const std::string& key) const;
+  iterator find(const std::string& key);
+  void insert_or_assign(const std::string& key, Value value);
+  void erase(const std::string& key);
+  void reserve(std::size_t size);
+  std::size_t size() const;
+  bool empty() const;
+
+  iterator begin();
+  iterator end();
+  const_iterator begin() const;
+  const_iterator end() const;
+
+ private:
+  std::vector<Entry> entries;
+  std::unordered_map<std::string, std::size_t> index;
 };
 
 struct Object {
@@ -66,8 +103,8 @@ struct List : public Object {
 };
 
 struct Table : public Object {
-  std::unordered_map<std::string, Value> hashMap;
-  Table(std::unordered_map<std::string, Value> hashMap) : hashMap{hashMap} {};
+  OrderedMap hashMap;
+  Table(OrderedMap hashMap) : hashMap{hashMap} {};
   ~Table() override {};
   std::uint64_t byteSize() override {
     return sizeof(Table);
@@ -83,25 +120,101 @@ struct Tuple : public Object {
   };
 };
 
+// The bytecode of one function. It points into the VM's program buffer,
+// which outlives every object, so loading a function literal in a loop does
+// not copy its body each time.
+struct Chunk {
+  std::uint8_t* code;
+  std::uint32_t length;
+};
+
+// A variable a function captured from an enclosing one. While that
+// function's frame is live the variable stays in its stack slot; when the
+// frame returns the upvalue is closed and keeps the value itself.
+struct Upvalue : public Object {
+  std::size_t slot;
+  bool open = true;
+  Value closed;
+  Upvalue(std::size_t slot) : slot{slot} {};
+  ~Upvalue() override {};
+  std::uint64_t byteSize() override {
+    return sizeof(Upvalue);
+  };
+};
+
+// Every load of a function literal makes a new Function, so a Closure
+// instruction right after the load can fill in `upvalues` without affecting
+// any other value
 struct Function : public Object {
   std::string name;
   std::uint16_t arity;
-  std::uint8_t* buffers;
-  Function(std::string name, std::uint16_t arity, std::uint8_t* buffers)
-      : name{name}, arity{arity}, buffers{buffers} {};
-  ~Function() override {
-    delete[] this->buffers;
-  };
+  Chunk chunk;
+  std::vector<Upvalue*> upvalues;
+  Function(std::string name, std::uint16_t arity, Chunk chunk)
+      : name{name}, arity{arity}, chunk{chunk} {};
+  ~Function() override {};
   std::uint64_t byteSize() override {
     return sizeof(Function);
   };
 };
 
+using NativeFn = Value (*)(VM& vm, std::vector<Value>& args);
+
+struct NativeFunction : public Object {
+  std::string name;
+  std::uint16_t arity;
+  NativeFn fn;
+  // Values bound when the native was created, e.g. the function a decorator
+  // wraps; the native reads them through VM::nativeData
+  std::vector<Value> data;
+  NativeFunction(std::string name,
+                 std::uint16_t arity,
+                 NativeFn fn,
+                 std::vector<Value> data)
+      : name{name}, arity{arity}, fn{fn}, data{data} {};
+  ~NativeFunction() override {};
+  std::uint64_t byteSize() override {
+    return sizeof(NativeFunction);
+  };
+};
+
+// A failure handed around as a value instead of being thrown
+// `cause` is the error this one was wrapped around, or _
+struct Error : public Object {
+  std::string message;
+  Value cause;
+  Error(std::string message, Value cause)
+      : message{message}, cause{cause} {};
+  ~Error() override {};
+  std::uint64_t byteSize() override {
+    return sizeof(Error);
+  };
+};
+
+// An exact decimal number, `units` scaled down by 10^scale
+struct Decimal : public Object {
+  std::int64_t units;
+  std::uint8_t scale;
+  Decimal(std::int64_t units, std::uint8_t scale)
+      : units{units}, scale{scale} {};
+  ~Decimal() override {};
+  std::uint64_t byteSize() override {
+    return sizeof(Decimal);
+  };
+};
+
+struct GCStats {
+  std::size_t nurseryCollections = 0;
+  std::size_t fullCollections = 0;
+  std::size_t freedObjects = 0;
+  std::size_t freedBytes = 0;
+};
+
 class GC {
  private:
   const std::size_t maxNurserySize = 1024 * 256;          // ~262KB
   const std::size_t maxRetirementHomeSize = 1024 * 2048;  // ~2MB
-  std::vector<Value>* stack;
+  std::vector<std::vector<Value>*> roots;
 
   std::size_t retirementHomeHeap = 0;
   std::forward_list<Object*> retirementHome;
@@ -109,24 +222,58 @@ class GC {
   std::size_t nurseryHeap = 0;
   std::forward_list<Object*> nursery;
 
-  void mayGCNursery();
-  void gcNursery();
+  std::size_t objects = 0;
+  std::size_t pauseCount = 0;
+  bool log = false;
+  GCStats stats;
 
-  void mayGCRetirementHome();
-  void gcRetirementHome();
+  void markRoots(Object* pending);
+  void sweepNursery();
+  void sweepRetirementHome();
+  void unmarkAll();
+  void logCollection(const char* kind, std::size_t freed);
 
-  void mayGC();
+  void mayGCNursery(Object* pending);
+  void gcNursery(Object* pending);
+
+  void mayGCRetirementHome(Object* pending);
+  void gcRetirementHome(Object* pending);
+
+  void mayGC(Object* pending);
 
  public:
-  GC(std::vector<Value>* stack) : stack{stack} {};
+  // Everything in `roots` is kept alive, e.g. the VM's stack and globals
+  GC(std::vector<std::vector<Value>*> roots) : roots{roots} {};
+  ~GC();
   void addObject(Object* object);
+
+  // Runs a full collection right away, even while paused
+  void collect();
+  // Allocations made while paused never trigger a collection, for code that
+  // holds objects the GC cannot see (e.g. inside natives)
+  void pause();
+  void resume();
+  // Lifts every pause at once and returns how many there were, for running
+  // code that roots its own objects in the middle of a native
+  std::size_t liftPause();
+  void restorePause(std::size_t count);
+  void setLog(bool log);
+  std::size_t heapBytes();
+  std::size_t objectCount();
+  GCStats& getStats();
+
   Value createString(std::string value);
   Value createAtom(std::string value);
   Value createList(std::vector<Value> elements);
-  Value createTable(std::unordered_map<std::string, Value> hashMap);
+  Value createTable(OrderedMap hashMap);
   Value createTuple(std::vector<Value> values);
-  Value createFunction(std::string name,
-                       std::uint16_t arity,
-                       std::uint8_t* buffers);
+  Value createNativeFunction(std::string name,
+                             std::uint16_t arity,
+                             NativeFn fn,
+                             std::vector<Value> data = {});
+  Value createFunction(std::string name, std::uint16_t arity, Chunk chunk);
+  Value createUpvalue(std::size_t slot);
+  Value createError(std::string message, Value cause = Value());
+  Value createDecimal(std::int64_t units, std::uint8_t scale);
 };
 }  // namespace flan
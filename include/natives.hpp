@@ -0,0 +1,19 @@
+#pragma once
+
+#include <string>
+#include <vector>
+
+#include "gc.hpp"
+
+namespace flan {
+
+// Registers every native module as a global of the given VM
+void defineNatives(VM &vm);
+
+// The members values other than tables answer to: `message`, `cause` and the
+// `chain` method of errors, and `name` and `arity` of functions. Empty for
+// everything else.
+const std::vector<std::string> &builtinMembers(Object *obj);
+// Reads one of the members listed by builtinMembers
+Value builtinMember(VM &vm, Object *obj, const std::string &key);
+}  // namespace flan
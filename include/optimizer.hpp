@@ -0,0 +1,14 @@
+#pragma once
+
+#include <cstdint>
+#include <vector>
+
+namespace flan {
+
+// Points every jump that lands on an unconditional Jmp straight at the end
+// of that chain, so a branch costs one dispatch however the compiler nested
+// it. `jumps` are the operand positions the Verifier collected. Jumps only
+// go forward, so every chain ends. Returns how many jumps were rewritten.
+std::size_t threadJumps(std::uint8_t *buffer,
+                        const std::vector<std::size_t> &jumps);
+}  // namespace flan
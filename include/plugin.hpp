@@ -0,0 +1,42 @@
+#pragma once
+
+#include <cstdint>
+
+#include "gc.hpp"
+
+namespace flan {
+
+// Bumped whenever Plugin, or anything a plugin reaches through it, changes
+// in a way that breaks plugins built against an older version
+const std::uint32_t PLUGIN_ABI_VERSION = 1;
+
+// Describes a shared library that import_native can load. The library
+// exports a C function named by PLUGIN_ENTRY that returns a pointer to one
+// of these. Plugins link against the same flan.hpp as the interpreter, so
+// they must be built with the same compiler and standard library.
+struct Plugin {
+  std::uint32_t abiVersion;  // PLUGIN_ABI_VERSION the plugin was built with
+  std::uint32_t valueSize;   // sizeof(Value), to catch mismatched builds
+  const char *name;
+  // Adds the plugin's natives to `module`, which import_native returns. Runs
+  // with the GC paused, like any native.
+  void (*init)(VM &vm, OrderedMap &module);
+};
+
+const char *const PLUGIN_ENTRY = "flan_plugin";
+using PluginEntry = const Plugin *(*)();
+}  // namespace flan
+
+// Defines the entry point of a plugin, e.g.
+//
+//   static void init(flan::VM &vm, flan::OrderedMap &module) {
+//     module["hello"] = vm.getGC().createNativeFunction("hello", 0, hello);
+//   }
+//   FLAN_PLUGIN("hello", init)
+#define FLAN_PLUGIN(name, init)                                  \
+  extern "C" const flan::Plugin *flan_plugin() {                 \
+    static const flan::Plugin plugin{flan::PLUGIN_ABI_VERSION,   \
+                                     sizeof(flan::Value), name,  \
+                                     init};                      \
+    return &plugin;                                              \
+  }
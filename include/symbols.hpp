@@ -0,0 +1,26 @@
+#pragma once
+
+#include <cstdint>
+#include <string>
+#include <unordered_map>
+#include <vector>
+
+namespace flan {
+
+using SymbolId = std::uint32_t;
+
+// Interns global names into dense ids. Once a name is interned everything
+// refers to it by id, and the name itself is only needed for messages.
+class SymbolTable {
+ public:
+  SymbolId intern(const std::string &name);
+  // Looks up a name without interning it
+  bool find(const std::string &name, SymbolId &id) const;
+  const std::string &name(SymbolId id) const;
+  std::size_t size() const;
+
+ private:
+  std::vector<std::string> names;
+  std::unordered_map<std::string, SymbolId> ids;
+};
+}  // namespace flan
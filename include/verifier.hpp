@@ -0,0 +1,61 @@
+#pragma once
+
+#include <cstdint>
+#include <map>
+#include <string>
+#include <vector>
+
+#include "vm.hpp"
+
+namespace flan {
+
+// Every nested function literal costs a level of recursion while verifying
+const auto MAX_FUNCTION_NESTING = 256;
+
+// Walks a whole bytecode file before the VM touches it, so that a corrupted
+// or truncated file is reported as an error instead of being executed.
+class Verifier {
+ public:
+  Verifier(std::uint8_t *buffer, std::size_t size);
+  bool verify();
+
+  std::string error;
+  // Where the offset operand of every jump is, for passes that rewrite them
+  std::vector<std::size_t> jumps;
+
+ private:
+  std::uint8_t *buffer;
+  std::size_t size;
+  std::size_t pos = 0;
+  std::size_t limit;
+  std::uint16_t errorInfoCount = 0;
+  std::uint16_t nameCount = 0;
+  std::size_t nesting = 0;
+  // The highest upvalue index the function being verified uses, plus one
+  std::uint32_t upvaluesUsed = 0;
+  // Set by verifyValue when the value is a function literal, with the number
+  // of upvalues it needs a Closure to supply
+  bool loadedFunction = false;
+  std::uint32_t loadedUpvalues = 0;
+
+  bool fail(std::string msg);
+  bool need(std::size_t count);
+  std::uint8_t readUInt8();
+  std::uint16_t readUInt16();
+  std::uint32_t readUInt32();
+
+  bool verifyHeader();
+  bool verifyErrorInfoSection();
+  bool verifyNameSection();
+  bool verifyCode(std::size_t end, std::int64_t depth, bool isFunction);
+  bool verifyValue();
+  bool verifyShortString();
+  bool verifyErrInfoIdx();
+  bool verifyNameIdx();
+  // Records that the function being verified reads upvalue `idx`
+  bool useUpvalue(std::uint16_t idx, bool isFunction);
+  bool verifyJump(std::size_t end,
+                  std::int64_t depth,
+                  std::map<std::size_t, std::int64_t> &targets);
+};
+}  // namespace flan
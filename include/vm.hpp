@@ -1,38 +1,146 @@
 #pragma once
 
+#include <algorithm>
+#include <deque>
 #include <filesystem>
+#include <istream>
+#include <ostream>
+#include <stdexcept>
+#include <string>
 #include <unordered_map>
 #include <vector>
 
 #include "gc.hpp"
+#include "symbols.hpp"
 
 using namespace flan;
 namespace fs = std::filesystem;
 
 namespace flan {
 
-const std::uint8_t VERSION[3] = {0, 0, 0};
+// Version of the bytecode format this runtime reads. Before 1.0 every minor
+// release may change the format, so only the patch number may differ.
+const std::uint8_t VERSION[3] = {0, 2, 0};
+
+// Bits of the feature byte that follows the version, one per optional part
+// of the instruction set a program was compiled to use
+const std::uint8_t FEATURE_LIST_MATH = 1 << 0;
+
+const std::uint8_t SUPPORTED_FEATURES =
+#ifdef FLAN_EXT_LIST_MATH
+    FEATURE_LIST_MATH |
+#endif
+    0;
+
+// Explains why bytecode with this header cannot run here, or returns
+// nothing when it can
+std::string incompatibility(const std::uint8_t version[3],
+                            std::uint8_t features);
 
 const std::uint8_t MAGIC_NUMBER[4] = {0x46, 0x4C, 0x41, 0x4E};
 
 const auto CALL_FRAMES_MAX = 64;
+const auto DEFAULT_MAX_CALL_DEPTH = 1024;
+// Frames printed at the top of a stack trace before the rest are elided
+const auto TRACE_FRAMES_SHOWN = 16;
+
+// Exit statuses of the flan process. A program that runs to completion
+// exits with 0 unless it calls os.exit.
+const auto EXIT_RUNTIME_ERROR = 1;  // uncaught error while running
+const auto EXIT_LOAD_ERROR = 2;     // unreadable or invalid bytecode
+
+struct VMOptions {
+  // Where to write the VM state when a runtime error is not handled; the
+  // dump is skipped when this is empty
+  fs::path postMortemPath;
+  // Print a line to stderr for every garbage collection
+  bool gcLog = false;
+  // Calls nested deeper than this are a runtime error rather than unbounded
+  // growth of the call stack
+  std::size_t maxCallDepth = DEFAULT_MAX_CALL_DEPTH;
+  // Let DefGlobal shadow a builtin, as if every definition used override
+  bool allowOverride = false;
+  // Stop with a runtime error after this many instructions; 0 means no limit
+  std::size_t maxInstructions = 0;
+};
 
 struct ErrorInfo {
   std::uint16_t line;
   std::string lineText;
 };
 
+// Thrown out of run() and call() for an error the program did not handle.
+// The stack is left as it was when the error was raised, so the VM must not
+// be run or called again.
+struct RuntimeError : std::runtime_error {
+  fs::path fileName;
+  std::uint16_t line;  // 0 when the failing instruction has no position
+  std::string lineText;
+  // Names of the functions that were running, innermost first
+  std::vector<std::string> trace;
+
+  RuntimeError(const std::string &msg,
+               fs::path fileName,
+               std::uint16_t line,
+               std::string lineText,
+               std::vector<std::string> trace);
+  // Prints the stack trace, then `file:line: message` and the line itself
+  void report(std::ostream &out) const;
+};
+
+// Position inside a block of bytecode. Reads through a cursor are checked
+// against `end`, so a malformed program can never run past its buffer.
+struct Cursor {
+  std::uint8_t *start;
+  std::uint8_t *end;
+  std::uint8_t *ip;
+  Cursor(std::uint8_t *start, std::size_t length)
+      : start{start}, end{start + length}, ip{start} {};
+  Cursor(Chunk chunk) : Cursor(chunk.code, chunk.length) {};
+};
+
 struct CallFrame {
-  std::uint8_t *retAddr;
-  std::uint16_t prevFrom;
+  Cursor retAddr;
+  std::size_t prevFrom;
   Function *function;
-  CallFrame(std::uint8_t *retAddr, Function *function, std::uint16_t prevFrom)
+  CallFrame(Cursor retAddr, Function *function, std::size_t prevFrom)
       : retAddr{retAddr}, prevFrom{prevFrom}, function{function} {};
 };
 
+// Returns a hint naming the closest known name, or nothing when no name is
+// close enough to be a plausible typo
+std::string didYouMean(const std::string &name,
+                       const std::vector<std::string> &known);
+
+// Same, over the keys of a map
+template <typename Map>
+std::string didYouMean(const std::string &name, const Map &known) {
+  std::vector<std::string> keys;
+  keys.reserve(known.size());
+  for (auto &pair : known) keys.push_back(pair.first);
+  return didYouMean(name, keys);
+}
+
+// The text a string or atom is ordered by, or nullptr for other objects.
+// Strings compare by their bytes, which for UTF-8 is code point order, so
+// `<` on strings and cmp agree.
+const std::string *orderedText(Object *obj);
+
+struct AtomStats {
+  std::size_t hits = 0;    // atom constants found in the per-site cache
+  std::size_t misses = 0;  // first reads of a site
+};
+
+// Lazy is a builtin module that has not been built yet
+enum class GlobalKind : std::uint8_t { Undefined, Builtin, Lazy, User };
+
+using LazyInit = void (*)(VM &vm);
+
 struct Stack {
   std::vector<Value> stack;
-  std::uint16_t from;
+  // Index of the running function's slot 0; the stack can grow well past
+  // what a 16-bit index reaches
+  std::size_t from;
 
   Stack();
   Value &last();
@@ -46,48 +154,119 @@ struct Stack {
 
 class VM {
  public:
-  VM(fs::path fileName);
+  VM(fs::path fileName, VMOptions options = {});
+  VM(std::istream &inputStream, fs::path fileName, VMOptions options = {});
   ~VM();
-  void run();
+  // Returns the value left on top of the stack at Halt, or _ if it is empty.
+  // The value stays on the stack, so objects live as long as the VM does.
+  // Throws RuntimeError if the program fails.
+  Value run();
+  // Prints the loaded bytecode instead of running it
+  void disassemble(std::ostream &out);
+  // Reads a global the program defined itself, ignoring builtins
+  bool userGlobal(const std::string &name, Value &value);
+
+  // Used by natives
+  GC &getGC();
+  void defineGlobal(std::string name, Value value);
+  // Defers building a builtin until it is first read. `init` must define
+  // `name` with defineGlobal.
+  void defineLazyGlobal(std::string name, LazyInit init);
+  // Every defined global, builtins included, in alphabetical order
+  std::vector<std::string> globalNames();
+  [[noreturn]] void nativeError(std::string msg);
+  // Calls a function value from inside a native and returns its result. The
+  // GC may run while the callee does, so any object the native still needs
+  // afterwards must be reachable from its arguments or passed to root().
+  Value call(Value callee, std::vector<Value> &args);
+  // Keeps a value alive until the current native returns
+  void root(Value value);
+  // The values bound to the native that is running
+  std::vector<Value> &nativeData();
+  const AtomStats &getAtomStats();
 
  private:
-  char *buffer;
+  char *buffer = nullptr;
+  std::size_t bufferSize = 0;
   Stack stack;
   std::vector<CallFrame> callframes;
   fs::path fileName;
   GC gc;
   std::vector<ErrorInfo> errorInfoList;
-  std::unordered_map<std::string, Value> globals;
+  // Globals are stored by symbol id. Builtins are read-only, so a program
+  // can only shadow one on purpose, and the user global then takes its slot.
+  SymbolTable symbols;
+  std::vector<Value> globals;
+  std::vector<GlobalKind> globalKinds;
+  std::unordered_map<SymbolId, LazyInit> lazyInits;
+  // The file's name pool, which global instructions index into, resolved to
+  // symbol ids once at load
+  std::vector<SymbolId> names;
+  std::uint16_t nativeErrInfoIdx = 0;
+  NativeFunction *currentNative = nullptr;
+  // A deque, so that borrowing at a new depth leaves outer buffers in place
+  std::deque<std::vector<Value>> argBuffers;
+  std::size_t argsDepth = 0;
+  std::size_t instructionCount = 0;
+  // Values kept alive for as long as the VM runs
+  std::vector<Value> constants;
+  std::unordered_map<std::string, Value> atoms;
+  std::unordered_map<const std::uint8_t *, Value> atomSites;
+  AtomStats atomStats;
+  // Upvalues still pointing at a stack slot, so that every function capturing
+  // the same variable shares one
+  std::vector<Value> openUpvalues;
+  VMOptions options;
 
-  void readErrorInfoSection();
-  bool checkMagicNumber(std::uint8_t *bufferPtr);
-  bool checkVersion(std::uint8_t *bufferPtr);
-  std::uint8_t readUInt8(std::uint8_t *bufferPtr);
-  std::uint16_t readUInt16(std::uint8_t *bufferPtr);
-  std::uint32_t readUInt32(std::uint8_t *bufferPtr);
+  void load(std::istream &inputStream);
+  void readErrorInfoSection(Cursor &cursor);
+  void readNameSection(Cursor &cursor);
+  SymbolId readName(Cursor &cursor);
+  bool checkMagicNumber(Cursor &cursor);
+  void checkVersion(Cursor &cursor);
+  std::uint8_t readUInt8(Cursor &cursor);
+  std::uint16_t readUInt16(Cursor &cursor);
+  std::uint32_t readUInt32(Cursor &cursor);
 
   void push(Value value);
   Value pop();
+  // Pops the top `count` values, returned in the order they were pushed
+  std::vector<Value> popN(std::size_t count);
 
-  void throwError(std::uint16_t errInfoIdx, std::string msg);
-  void throwError(std::string msg);
+  void writePostMortem(ErrorInfo &errInfo, std::string &msg);
+  [[noreturn]] void throwError(std::uint16_t errInfoIdx, std::string msg);
+  [[noreturn]] void throwError(std::string msg);
+  // For runtime errors raised by an instruction without an errInfo operand
+  [[noreturn]] void throwRuntimeError(std::string msg);
+  std::vector<std::string> stackTrace();
+  [[noreturn]] void undefinedGlobal(std::uint16_t errInfoIdx,
+                                    SymbolId undefined);
+  SymbolId globalSlot(const std::string &name);
+  void checkAssignable(SymbolId id, std::uint16_t errInfoIdx);
+  void initLazy(SymbolId id);
 
-  std::string readShortString(std::uint8_t *bufferPtr);
-  Value readValue(std::uint8_t *bufferPtr);
-  Value readInteger(std::uint8_t *bufferPtr);
-  Value readFloat(std::uint8_t *bufferPtr);
-  Value readBool(std::uint8_t *bufferPtr);
+  std::string readShortString(Cursor &cursor);
+  Value readValue(Cursor &cursor);
+  Value readInteger(Cursor &cursor);
+  Value readLongInteger(Cursor &cursor);
+  Value readFloat(Cursor &cursor);
+  Value readBool(Cursor &cursor);
   Value readEmpty();
-  Value readString(std::uint8_t *bufferPtr);
-  Value readAtom(std::uint8_t *bufferPtr);
-  Value readFunction(std::uint8_t *bufferPtr);
-  std::uint8_t *readFunctionBody(std::uint8_t *bufferPtr);
+  // Long strings have a 4-byte length instead of 2
+  Value readString(Cursor &cursor, bool isLong);
+  Value readAtom(Cursor &cursor);
+  Value readFunction(Cursor &cursor);
+  Chunk readFunctionBody(Cursor &cursor, std::uint32_t length);
 
   Value performAdd(std::uint16_t errInfoIdx);
   Value performSub(std::uint16_t errInfoIdx);
   Value performMul(std::uint16_t errInfoIdx);
   Value performDiv(std::uint16_t errInfoIdx);
   Value performMod(std::uint16_t errInfoIdx);
+  bool valuesEqual(Value &left,
+                   Value &right,
+                   std::uint16_t errInfoIdx,
+                   int depth);
   Value performEq(std::uint16_t errInfoIdx);
   Value performNEq(std::uint16_t errInfoIdx);
   Value performLT(std::uint16_t errInfoIdx);
@@ -97,12 +276,39 @@ class VM {
   Value performAnd();
   Value performOr();
 
-  void jumpForward(std::uint8_t *bufferPtr, std::size_t offset);
+  Value execute(Cursor &cursor, std::size_t returnDepth);
+  void defineUserGlobal(SymbolId id,
+                        Value value,
+                        bool override,
+                        std::uint16_t errInfoIdx);
+  void jumpForward(Cursor &cursor, std::size_t offset);
+
+  Value getMember(Value value, std::string key, std::uint16_t errInfoIdx);
+  std::size_t resolveIndex(Value key,
+                           std::size_t size,
+                           std::uint16_t errInfoIdx);
+  Value getIndex(Value value, Value key, std::uint16_t errInfoIdx);
+  Value increment(Value value, std::int64_t delta, std::uint16_t errInfoIdx);
+  Upvalue *captureUpvalue(std::size_t slot);
+  Value &upvalueRef(Upvalue *upvalue);
+  void closeUpvalues(std::size_t from);
+  void destructureList(Value value,
+                       std::uint32_t count,
+                       std::uint16_t errInfoIdx);
+  void setIndex(Value value,
+                Value key,
+                Value newValue,
+                std::uint16_t errInfoIdx);
 
-  void callFunc(std::uint8_t *bufferPtr,
+  void callFunc(Cursor &cursor,
                 Value couldBeFunc,
                 std::uint16_t argCount,
                 std::uint16_t errInfoIdx);
+  void callNative(NativeFunction *native,
+                  std::uint16_t argCount,
+                  std::uint16_t errInfoIdx);
+  void callExtension(std::uint8_t id, std::uint16_t errInfoIdx);
+  std::vector<Value> &borrowArgs();
 };
 
 enum class InstructionType : std::uint8_t {
@@ -153,6 +359,26 @@ enum class InstructionType : std::uint8_t {
   CallFn,
   RetFn,
   EndFn,
+  CallMember,
+  GetIndex,
+  SetIndex,
+  OverrideGlobal,
+  DestructureList,
+  DestructureObject,
+  Dup2,
+  IncLocal,
+  DecLocal,
+  IncGlobal,
+  DecGlobal,
+  Closure,
+  GetUpvalue,
+  SetUpvalue,
+  CloseUpvalue,
+  // Jump keeping the condition on the stack, or pop it and fall through, so
+  // `and` and `or` evaluate to their deciding operand
+  JzOrPop,
+  JnzOrPop,
+  Ext = 254,  // see extensions.hpp
   Halt = 255,
 };
 }  // namespace flan
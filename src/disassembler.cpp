@@ -0,0 +1,375 @@
+#include "disassembler.hpp"
+
+#include <cstring>
+#include <iomanip>
+#include <sstream>
+#include <unordered_map>
+
+#include "extensions.hpp"
+
+using namespace flan;
+
+static const std::unordered_map<InstructionType, const char *> NAMES = {
+    {InstructionType::LoadNeg1, "LoadNeg1"},
+    {InstructionType::Load0, "Load0"},
+    {InstructionType::Load1, "Load1"},
+    {InstructionType::Load2, "Load2"},
+    {InstructionType::Load3, "Load3"},
+    {InstructionType::Load4, "Load4"},
+    {InstructionType::Load5, "Load5"},
+    {InstructionType::Load, "Load"},
+    {InstructionType::Push, "Push"},
+    {InstructionType::Pop, "Pop"},
+    {InstructionType::PopN, "PopN"},
+    {InstructionType::Nip, "Nip"},
+    {InstructionType::NipN, "NipN"},
+    {InstructionType::Dup, "Dup"},
+    {InstructionType::Add, "Add"},
+    {InstructionType::Sub, "Sub"},
+    {InstructionType::Mul, "Mul"},
+    {InstructionType::Div, "Div"},
+    {InstructionType::Mod, "Mod"},
+    {InstructionType::Eq, "Eq"},
+    {InstructionType::NEq, "NEq"},
+    {InstructionType::LT, "LT"},
+    {InstructionType::LTE, "LTE"},
+    {InstructionType::GT, "GT"},
+    {InstructionType::GTE, "GTE"},
+    {InstructionType::And, "And"},
+    {InstructionType::Or, "Or"},
+    {InstructionType::Not, "Not"},
+    {InstructionType::Negate, "Negate"},
+    {InstructionType::Jmp, "Jmp"},
+    {InstructionType::Jz, "Jz"},
+    {InstructionType::Jnz, "Jnz"},
+    {InstructionType::InitList, "InitList"},
+    {InstructionType::InitTable, "InitTable"},
+    {InstructionType::InitTup, "InitTup"},
+    {InstructionType::IdxListOrTup, "IdxListOrTup"},
+    {InstructionType::SetList, "SetList"},
+    {InstructionType::GetMember, "GetMember"},
+    {InstructionType::SetMember, "SetMember"},
+    {InstructionType::DefGlobal, "DefGlobal"},
+    {InstructionType::GetGlobal, "GetGlobal"},
+    {InstructionType::SetGlobal, "SetGlobal"},
+    {InstructionType::GetLocal, "GetLocal"},
+    {InstructionType::SetLocal, "SetLocal"},
+    {InstructionType::CallFn, "CallFn"},
+    {InstructionType::RetFn, "RetFn"},
+    {InstructionType::EndFn, "EndFn"},
+    {InstructionType::CallMember, "CallMember"},
+    {InstructionType::GetIndex, "GetIndex"},
+    {InstructionType::SetIndex, "SetIndex"},
+    {InstructionType::OverrideGlobal, "OverrideGlobal"},
+    {InstructionType::DestructureList, "DestructureList"},
+    {InstructionType::DestructureObject, "DestructureObject"},
+    {InstructionType::Dup2, "Dup2"},
+    {InstructionType::IncLocal, "IncLocal"},
+    {InstructionType::DecLocal, "DecLocal"},
+    {InstructionType::IncGlobal, "IncGlobal"},
+    {InstructionType::DecGlobal, "DecGlobal"},
+    {InstructionType::Closure, "Closure"},
+    {InstructionType::GetUpvalue, "GetUpvalue"},
+    {InstructionType::SetUpvalue, "SetUpvalue"},
+    {InstructionType::CloseUpvalue, "CloseUpvalue"},
+    {InstructionType::JzOrPop, "JzOrPop"},
+    {InstructionType::JnzOrPop, "JnzOrPop"},
+    {InstructionType::Ext, "Ext"},
+    {InstructionType::Halt, "Halt"},
+};
+
+Disassembler::Disassembler(std::uint8_t *buffer,
+                           std::size_t size,
+                           std::ostream &out)
+    : buffer{buffer}, size{size}, out{out} {}
+
+void Disassembler::disassemble() {
+  this->header();
+  this->code(this->size, 0);
+}
+
+std::uint8_t Disassembler::readUInt8() {
+  return this->buffer[this->pos++];
+}
+
+std::uint16_t Disassembler::readUInt16() {
+  auto low_byte = this->readUInt8();
+  auto high_byte = this->readUInt8();
+  return static_cast<std::uint16_t>(low_byte) |
+         (static_cast<std::uint16_t>(high_byte) << 8);
+}
+
+std::uint32_t Disassembler::readUInt32() {
+  std::uint32_t value = 0;
+  for (auto i = 0; i < 4; i++)
+    value |= static_cast<std::uint32_t>(this->readUInt8()) << (i * 8);
+  return value;
+}
+
+std::string Disassembler::readShortString() {
+  auto length = this->readUInt8();
+  std::string str(reinterpret_cast<char *>(this->buffer + this->pos), length);
+  this->pos += length;
+  return str;
+}
+
+void Disassembler::header() {
+  this->pos = 4;
+  auto major = this->readUInt8();
+  auto minor = this->readUInt8();
+  auto patch = this->readUInt8();
+  auto features = this->readUInt8();
+  this->out << "version " << static_cast<int>(major) << '.'
+            << static_cast<int>(minor) << '.' << static_cast<int>(patch)
+            << ", features 0x" << std::hex << static_cast<int>(features)
+            << std::dec << "\n";
+
+  auto errorInfoCount = this->readUInt16();
+  this->out << "error info (" << errorInfoCount << "):\n";
+  for (auto i = 0; i < errorInfoCount; i++) {
+    auto line = this->readUInt16();
+    auto length = this->readUInt16();
+    std::string text(reinterpret_cast<char *>(this->buffer + this->pos),
+                     length);
+    this->pos += length;
+    this->out << "  " << i << ": line " << line << ": " << text << "\n";
+  }
+
+  auto nameCount = this->readUInt16();
+  this->out << "names (" << nameCount << "):\n";
+  for (auto i = 0; i < nameCount; i++) {
+    this->names.push_back(this->readShortString());
+    this->out << "  " << i << ": " << this->names.back() << "\n";
+  }
+  this->out << "code:\n";
+}
+
+std::string Disassembler::name() {
+  auto idx = this->readUInt16();
+  return this->names[idx];
+}
+
+void Disassembler::code(std::size_t end, int indent) {
+  while (this->pos < end) {
+    auto start = this->pos;
+    auto instType = static_cast<InstructionType>(this->readUInt8());
+    std::stringstream operands;
+    std::vector<Body> bodies;
+
+    switch (instType) {
+      case InstructionType::Load:
+        operands << this->value(bodies);
+        break;
+
+      case InstructionType::Push: {
+        auto length = this->readUInt8();
+        for (auto i = 0; i < length; i++)
+          operands << (i == 0 ? "" : ", ") << this->value(bodies);
+        break;
+      }
+
+      case InstructionType::PopN:
+      case InstructionType::NipN:
+        operands << static_cast<int>(this->readUInt8());
+        break;
+
+      case InstructionType::Add:
+      case InstructionType::Sub:
+      case InstructionType::Mul:
+      case InstructionType::Div:
+      case InstructionType::Mod:
+      case InstructionType::Eq:
+      case InstructionType::NEq:
+      case InstructionType::LT:
+      case InstructionType::LTE:
+      case InstructionType::GT:
+      case InstructionType::GTE:
+      case InstructionType::GetIndex:
+      case InstructionType::SetIndex:
+        operands << "@" << this->readUInt16();
+        break;
+
+      // Offsets count from the end of the instruction, so the target is
+      // printed as an absolute position instead
+      case InstructionType::Jmp:
+      case InstructionType::Jz:
+      case InstructionType::Jnz:
+      case InstructionType::JzOrPop:
+      case InstructionType::JnzOrPop: {
+        auto offset = this->readUInt32();
+        operands << "-> " << std::setw(4) << std::setfill('0') << std::hex
+                 << this->pos + offset << std::dec;
+        break;
+      }
+
+      case InstructionType::InitList:
+      case InstructionType::InitTup:
+        operands << this->readUInt32();
+        break;
+
+      case InstructionType::InitTable: {
+        auto length = this->readUInt32();
+        for (std::uint32_t i = 0; i < length; i++)
+          operands << (i == 0 ? "" : ", ") << this->readShortString();
+        break;
+      }
+
+      case InstructionType::IdxListOrTup:
+      case InstructionType::SetList:
+      case InstructionType::DestructureList: {
+        auto errInfoIdx = this->readUInt16();
+        operands << "@" << errInfoIdx << " " << this->readUInt32();
+        break;
+      }
+
+      case InstructionType::GetMember:
+      case InstructionType::SetMember: {
+        auto errInfoIdx = this->readUInt16();
+        operands << "@" << errInfoIdx << " " << this->readShortString();
+        break;
+      }
+
+      case InstructionType::DefGlobal:
+      case InstructionType::GetGlobal:
+      case InstructionType::SetGlobal:
+      case InstructionType::OverrideGlobal:
+      case InstructionType::IncGlobal:
+      case InstructionType::DecGlobal: {
+        auto errInfoIdx = this->readUInt16();
+        operands << "@" << errInfoIdx << " " << this->name();
+        break;
+      }
+
+      case InstructionType::GetLocal:
+      case InstructionType::SetLocal:
+      case InstructionType::GetUpvalue:
+      case InstructionType::SetUpvalue:
+        operands << this->readUInt16();
+        break;
+
+      // Captured locals print as `local n`, shared upvalues as `upvalue n`
+      case InstructionType::Closure: {
+        auto count = this->readUInt16();
+        for (auto i = 0; i < count; i++) {
+          auto isLocal = this->readUInt8();
+          operands << (i == 0 ? "" : ", ") << (isLocal ? "local " : "upvalue ")
+                   << this->readUInt16();
+        }
+        break;
+      }
+
+      case InstructionType::IncLocal:
+      case InstructionType::DecLocal:
+      case InstructionType::CallFn: {
+        auto errInfoIdx = this->readUInt16();
+        operands << "@" << errInfoIdx << " " << this->readUInt16();
+        break;
+      }
+
+      case InstructionType::CallMember: {
+        auto errInfoIdx = this->readUInt16();
+        auto key = this->readShortString();
+        operands << "@" << errInfoIdx << " " << key << " "
+                 << this->readUInt16();
+        break;
+      }
+
+      case InstructionType::DestructureObject: {
+        operands << "@" << this->readUInt16();
+        auto count = this->readUInt32();
+        for (std::uint32_t i = 0; i < count; i++)
+          operands << (i == 0 ? " " : ", ") << this->readShortString();
+        break;
+      }
+
+      case InstructionType::Ext: {
+        auto extension = findExtension(this->readUInt8());
+        operands << "@" << this->readUInt16() << " " << extension->name;
+        break;
+      }
+
+      default:
+        break;
+    }
+
+    std::string line = NAMES.at(instType);
+    if (operands.tellp() > 0)
+      line += std::string(line.size() < 18 ? 18 - line.size() : 1, ' ') +
+              operands.str();
+    this->out << std::setw(4) << std::setfill('0') << std::hex << start
+              << std::dec << "  " << std::string(indent * 2, ' ') << line
+              << "\n";
+
+    auto resume = this->pos;
+    for (auto &body : bodies) {
+      this->pos = body.start;
+      this->code(body.end, indent + 1);
+    }
+    this->pos = resume;
+  }
+}
+
+std::string Disassembler::value(std::vector<Body> &bodies) {
+  std::stringstream ss;
+  auto type = this->readUInt8();
+
+  switch (type) {
+    case 0:
+    case 7: {
+      std::uint64_t result = 0;
+      for (auto i = 0; i < (type == 7 ? 8 : 4); i++)
+        result |= static_cast<std::uint64_t>(this->readUInt8()) << (i * 8);
+      ss << static_cast<std::int64_t>(result);
+      break;
+    }
+    case 1: {
+      double result;
+      std::memcpy(&result, this->buffer + this->pos, 8);
+      this->pos += 8;
+      ss << Value(result).toDbgString();
+      break;
+    }
+    case 2:
+      ss << (this->readUInt8() == 1 ? "true" : "false");
+      break;
+    case 3:
+      ss << "_";
+      break;
+    case 4:
+    case 8: {
+      std::uint32_t length =
+          type == 8 ? this->readUInt32() : this->readUInt16();
+      std::string str(reinterpret_cast<char *>(this->buffer + this->pos),
+                      length);
+      this->pos += length;
+      ss << '"';
+      for (auto c : str) {
+        if (c == '\n')
+          ss << "\\n";
+        else if (c == '\t')
+          ss << "\\t";
+        else if (c == '"' || c == '\\')
+          ss << '\\' << c;
+        else
+          ss << c;
+      }
+      ss << '"';
+      break;
+    }
+    case 5:
+      ss << ":" << this->readShortString();
+      break;
+    case 6: {
+      auto fnName = this->readShortString();
+      auto arity = this->readUInt16();
+      auto length = this->readUInt32();
+      bodies.push_back({this->pos, this->pos + length});
+      // Skips the body and the EndFn after it
+      this->pos += length + 1;
+      ss << "<function " << fnName << "/" << arity << ">";
+      break;
+    }
+  }
+
+  return ss.str();
+}
@@ -0,0 +1,63 @@
+#include "extensions.hpp"
+
+#include <typeinfo>
+#include <unordered_map>
+#include <variant>
+
+#include "vm.hpp"
+
+using namespace flan;
+
+// Ids are never reused, so an id keeps its meaning even after the
+// experiment behind it is dropped. Each experiment is compiled only when
+// its FLAN_EXT_* macro is defined.
+enum ExtensionId : std::uint8_t {
+  LIST_SUM = 0x01,
+};
+
+#ifdef FLAN_EXT_LIST_MATH
+// Sums a list of numbers in one instruction instead of a loop of Adds
+static Value listSum(VM &vm, std::vector<Value> &operands) {
+  auto &list = operands[0];
+  if (!std::holds_alternative<Object *>(list.value) ||
+      typeid(*std::get<Object *>(list.value)) != typeid(List))
+    vm.nativeError("list.sum expects a list but got " + list.toDbgString());
+
+  auto &elements =
+      static_cast<List *>(std::get<Object *>(list.value))->elements;
+  std::int64_t intSum = 0;
+  double floatSum = 0;
+  bool isFloat = false;
+  for (auto &element : elements) {
+    if (std::holds_alternative<std::int64_t>(element.value)) {
+      auto n = std::get<std::int64_t>(element.value);
+      if (isFloat) {
+        floatSum += static_cast<double>(n);
+      } else if (__builtin_add_overflow(intSum, n, &intSum)) {
+        vm.nativeError("Integer overflow in list.sum");
+      }
+    } else if (std::holds_alternative<double>(element.value)) {
+      if (!isFloat) floatSum = static_cast<double>(intSum);
+      isFloat = true;
+      floatSum += std::get<double>(element.value);
+    } else {
+      vm.nativeError("list.sum expects numbers but got " +
+                     element.toDbgString());
+    }
+  }
+
+  return isFloat ? Value(floatSum) : Value(intSum);
+}
+#endif
+
+static const std::unordered_map<std::uint8_t, Extension> EXTENSIONS = {
+#ifdef FLAN_EXT_LIST_MATH
+    {LIST_SUM, {"list.sum", 1, listSum}},
+#endif
+};
+
+const Extension *flan::findExtension(std::uint8_t id) {
+  auto found = EXTENSIONS.find(id);
+  if (found == EXTENSIONS.end()) return nullptr;
+  return &found->second;
+}
@@ -1,3 +1,152 @@
-int main() {
-  return 0;
+#include <cstdlib>
+#include <cstring>
+#include <iostream>
+#include <string>
+#include <typeinfo>
+#include <variant>
+#include <vector>
+
+#include "flan.hpp"
+
+static int usage() {
+  std::cerr << "Usage: flan [options] <file> [args...]\n"
+            << "       flan [options] - [args...]    read the program from "
+               "stdin\n\n"
+            << "Options:\n"
+            << "  --post-mortem <dump>    write the VM state to <dump> on an "
+               "uncaught error\n"
+            << "  --gc-log                print every garbage collection\n"
+            << "  --max-call-depth <n>    fail calls nested deeper than <n> "
+               "(default "
+            << DEFAULT_MAX_CALL_DEPTH << ")\n"
+            << "  --print-result          print the value the program halts "
+               "with\n"
+            << "  --max-instructions <n>  stop with an error after <n> "
+               "instructions\n"
+            << "  --allow-override        let global definitions shadow "
+               "builtins\n"
+            << "  --dump-bytecode         print the program's instructions "
+               "instead of running it\n\n"
+            << "If the program defines a function main, it is called with "
+               "the list of args\n"
+            << "after the top level has run. An integer result is the exit "
+               "status, an error\n"
+            << "is printed with its causes and exits with "
+            << EXIT_RUNTIME_ERROR << ", and anything else exits with 0.\n";
+  return EXIT_LOAD_ERROR;
+}
+
+static Error *asError(Value value) {
+  if (!std::holds_alternative<Object *>(value.value)) return nullptr;
+  auto obj = std::get<Object *>(value.value);
+  return typeid(*obj) == typeid(Error) ? static_cast<Error *>(obj) : nullptr;
+}
+
+// Calls the program's main, if it has one, and maps its result to an exit
+// status; `result` is left alone when there is no main
+static int runMain(VM &vm, std::vector<std::string> &args, Value &result) {
+  Value main;
+  if (!vm.userGlobal("main", main) ||
+      !std::holds_alternative<Object *>(main.value) ||
+      typeid(*std::get<Object *>(main.value)) != typeid(Function))
+    return EXIT_SUCCESS;
+
+  auto arity = static_cast<Function *>(std::get<Object *>(main.value))->arity;
+  if (arity > 1) {
+    std::cerr << "Error: main takes " << arity
+              << " arguments but only a list of args can be given\n";
+    return EXIT_RUNTIME_ERROR;
+  }
+
+  // Nothing roots the strings until the call pushes the list
+  auto &gc = vm.getGC();
+  std::vector<Value> callArgs;
+  if (arity == 1) {
+    gc.pause();
+    std::vector<Value> strings;
+    for (auto &arg : args) strings.push_back(gc.createString(arg));
+    callArgs.push_back(gc.createList(std::move(strings)));
+    gc.resume();
+  }
+  result = vm.call(main, callArgs);
+
+  if (std::holds_alternative<std::int64_t>(result.value))
+    return static_cast<int>(std::get<std::int64_t>(result.value));
+  if (auto error = asError(result)) {
+    std::cerr << "Error: " << error->message;
+    while ((error = asError(error->cause)))
+      std::cerr << "\n  caused by: " << error->message;
+    std::cerr << std::endl;
+    return EXIT_RUNTIME_ERROR;
+  }
+  return EXIT_SUCCESS;
+}
+
+int main(int argc, char **argv) {
+  VMOptions options;
+  const char *fileName = nullptr;
+  std::vector<std::string> args;
+  bool printResult = false;
+  bool dumpBytecode = false;
+
+  for (auto i = 1; i < argc; i++) {
+    if (std::strcmp(argv[i], "--post-mortem") == 0) {
+      if (++i == argc) return usage();
+      options.postMortemPath = argv[i];
+    } else if (std::strcmp(argv[i], "--gc-log") == 0) {
+      options.gcLog = true;
+    } else if (std::strcmp(argv[i], "--max-call-depth") == 0) {
+      if (++i == argc) return usage();
+      char *end;
+      auto depth = std::strtoul(argv[i], &end, 10);
+      if (*argv[i] == '\0' || *end != '\0') return usage();
+      options.maxCallDepth = depth;
+    } else if (std::strcmp(argv[i], "--max-instructions") == 0) {
+      if (++i == argc) return usage();
+      char *end;
+      auto count = std::strtoul(argv[i], &end, 10);
+      if (*argv[i] == '\0' || *end != '\0') return usage();
+      options.maxInstructions = count;
+    } else if (std::strcmp(argv[i], "--print-result") == 0) {
+      printResult = true;
+    } else if (std::strcmp(argv[i], "--allow-override") == 0) {
+      options.allowOverride = true;
+    } else if (std::strcmp(argv[i], "--dump-bytecode") == 0) {
+      dumpBytecode = true;
+    } else {
+      // Everything after the file belongs to the program
+      fileName = argv[i];
+      args.assign(argv + i + 1, argv + argc);
+      break;
+    }
+  }
+
+  if (fileName == nullptr) return usage();
+
+  // Nothing in the runtime uses C stdio, so skip keeping it in sync
+  std::ios::sync_with_stdio(false);
+
+  auto run = [&](VM &vm) {
+    if (dumpBytecode) {
+      vm.disassemble(std::cout);
+      return EXIT_SUCCESS;
+    }
+    try {
+      auto result = vm.run();
+      auto status = runMain(vm, args, result);
+      if (printResult) std::cout << result.toDbgString() << '\n';
+      return status;
+    } catch (const RuntimeError &error) {
+      std::cout.flush();
+      error.report(std::cerr);
+      return EXIT_RUNTIME_ERROR;
+    }
+  };
+
+  if (std::strcmp(fileName, "-") == 0) {
+    VM vm(std::cin, "<stdin>", options);
+    return run(vm);
+  }
+  VM vm(fileName, options);
+  return run(vm);
 }
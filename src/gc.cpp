@@ -1,5 +1,7 @@
 #include "gc.hpp"
 
+#include <charconv>
+#include <iostream>
 #include <string>
 #include <typeinfo>
 #include <variant>
@@ -9,114 +11,306 @@ using namespace flan;
 void Object::mark() {
   if (this->marked) return;
   this->marked = true;
+
+  if (typeid(*this) == typeid(List)) {
+    for (auto &element : static_cast<List *>(this)->elements) element.mark();
+  } else if (typeid(*this) == typeid(Table)) {
+    for (auto &pair : static_cast<Table *>(this)->hashMap) pair.second.mark();
+  } else if (typeid(*this) == typeid(Tuple)) {
+    for (auto &value : static_cast<Tuple *>(this)->values) value.mark();
+  } else if (typeid(*this) == typeid(NativeFunction)) {
+    for (auto &value : static_cast<NativeFunction *>(this)->data)
+      value.mark();
+  } else if (typeid(*this) == typeid(Error)) {
+    static_cast<Error *>(this)->cause.mark();
+  } else if (typeid(*this) == typeid(Function)) {
+    for (auto upvalue : static_cast<Function *>(this)->upvalues)
+      upvalue->mark();
+  } else if (typeid(*this) == typeid(Upvalue)) {
+    static_cast<Upvalue *>(this)->closed.mark();
+  }
 }
 
-void GC::mayGC() {
-  mayGCNursery();
-  mayGCRetirementHome();
+OrderedMap::OrderedMap(std::initializer_list<Entry> entries) {
+  for (auto &entry : entries) this->insert_or_assign(entry.first, entry.second);
 }
 
-void GC::mayGCNursery() {
-  if (this->nurseryHeap >= this->maxNurserySize) this->gcNursery();
+Value &OrderedMap::operator[](const std::string &key) {
+  auto found = this->index.find(key);
+  if (found != this->index.end()) return this->entries[found->second].second;
+
+  this->index[key] = this->entries.size();
+  this->entries.emplace_back(key, Value());
+  return this->entries.back().second;
 }
 
-void GC::mayGCRetirementHome() {
-  if (this->retirementHomeHeap >= this->maxRetirementHomeSize)
-    this->gcRetirementHome();
+std::size_t OrderedMap::count(const std::string &key) const {
+  return this->index.count(key);
 }
 
-void GC::gcNursery() {
-  // Mark all
-  for (auto value : *this->stack) {
-    if (std::holds_alternative<Object *>(value.value))
-      std::get<Object *>(value.value)->mark();
-  }
+OrderedMap::iterator OrderedMap::find(const std::string &key) {
+  auto found = this->index.find(key);
+  if (found == this->index.end()) return this->entries.end();
+  return this->entries.begin() + found->second;
+}
 
-  // Sweep
-  for (auto it = this->nursery.begin(); it != this->nursery.end(); it++) {
-    auto obj = *it;
-    this->nursery.erase_after(it);
-    this->nurseryHeap -= obj->byteSize();
+void OrderedMap::insert_or_assign(const std::string &key, Value value) {
+  (*this)[key] = value;
+}
 
-    if (!obj->marked) {
-      delete obj;  // Clear memory :)
-    } else {
-      obj->marked = false;
-      this->retirementHome.push_front(obj);
-    }
-  }
+void OrderedMap::erase(const std::string &key) {
+  auto found = this->index.find(key);
+  if (found == this->index.end()) return;
+
+  auto position = found->second;
+  this->index.erase(found);
+  this->entries.erase(this->entries.begin() + position);
+  for (auto &pair : this->index)
+    if (pair.second > position) pair.second--;
 }
 
-void GC::gcRetirementHome() {
-  // Mark all
-  for (auto value : *this->stack) {
-    if (std::holds_alternative<Object *>(value.value))
-      std::get<Object *>(value.value)->mark();
-  }
+void OrderedMap::reserve(std::size_t size) {
+  this->entries.reserve(size);
+  this->index.reserve(size);
+}
+
+std::size_t OrderedMap::size() const {
+  return this->entries.size();
+}
+
+bool OrderedMap::empty() const {
+  return this->entries.empty();
+}
+
+OrderedMap::iterator OrderedMap::begin() {
+  return this->entries.begin();
+}
 
-  // Sweep
+OrderedMap::iterator OrderedMap::end() {
+  return this->entries.end();
+}
+
+OrderedMap::const_iterator OrderedMap::begin() const {
+  return this->entries.begin();
+}
+
+OrderedMap::const_iterator OrderedMap::end() const {
+  return this->entries.end();
+}
+
+void Value::mark() {
+  if (std::holds_alternative<Object *>(this->value))
+    std::get<Object *>(this->value)->mark();
+}
+
+GC::~GC() {
+  for (auto obj : this->nursery) delete obj;
+  for (auto obj : this->retirementHome) delete obj;
+}
+
+void GC::mayGC(Object *pending) {
+  if (this->pauseCount > 0) return;
+  mayGCNursery(pending);
+  mayGCRetirementHome(pending);
+}
+
+void GC::mayGCNursery(Object *pending) {
+  if (this->nurseryHeap >= this->maxNurserySize) this->gcNursery(pending);
+}
+
+void GC::mayGCRetirementHome(Object *pending) {
+  if (this->retirementHomeHeap >= this->maxRetirementHomeSize)
+    this->gcRetirementHome(pending);
+}
+
+void GC::markRoots(Object *pending) {
+  for (auto root : this->roots)
+    for (auto &value : *root) value.mark();
+  if (pending != nullptr) pending->mark();
+}
+
+void GC::sweepNursery() {
   for (auto obj : this->nursery) {
+    auto size = obj->byteSize();
     if (!obj->marked) {
-      this->retirementHome.remove(obj);
-      this->retirementHomeHeap -= obj->byteSize();
       delete obj;  // Clear memory :)
+      this->objects--;
+      this->stats.freedObjects++;
+      this->stats.freedBytes += size;
     } else {
-      obj->marked = false;
+      this->retirementHome.push_front(obj);
+      this->retirementHomeHeap += size;
     }
   }
+
+  this->nursery.clear();
+  this->nurseryHeap = 0;
+}
+
+void GC::sweepRetirementHome() {
+  this->retirementHome.remove_if([this](Object *obj) {
+    if (obj->marked) return false;
+
+    auto size = obj->byteSize();
+    this->retirementHomeHeap -= size;
+    this->objects--;
+    this->stats.freedObjects++;
+    this->stats.freedBytes += size;
+    delete obj;  // Clear memory :)
+    return true;
+  });
+}
+
+void GC::unmarkAll() {
+  // Everything that survived has been promoted by now
+  for (auto obj : this->retirementHome) obj->marked = false;
+}
+
+void GC::gcNursery(Object *pending) {
+  auto freedBefore = this->stats.freedObjects;
+
+  this->markRoots(pending);
+  this->sweepNursery();
+  this->unmarkAll();
+
+  this->stats.nurseryCollections++;
+  this->logCollection("nursery", this->stats.freedObjects - freedBefore);
+}
+
+void GC::gcRetirementHome(Object *pending) {
+  auto freedBefore = this->stats.freedObjects;
+
+  this->markRoots(pending);
+  this->sweepRetirementHome();
+  this->sweepNursery();
+  this->unmarkAll();
+
+  this->stats.fullCollections++;
+  this->logCollection("full", this->stats.freedObjects - freedBefore);
+}
+
+void GC::logCollection(const char *kind, std::size_t freed) {
+  if (!this->log) return;
+  std::cerr << "[gc] " << kind << " collection freed " << freed
+            << " objects, " << this->objects << " objects ("
+            << this->heapBytes() << " bytes) left" << std::endl;
 }
 
 void GC::addObject(Object *object) {
-  this->mayGC();
   this->nursery.push_front(object);
+  this->nurseryHeap += object->byteSize();
+  this->objects++;
+
+  // The new object is not reachable from any root yet, but whatever it
+  // holds (e.g. the popped elements of a list literal) has to survive
+  this->mayGC(object);
+}
+
+void GC::collect() {
+  this->gcRetirementHome(nullptr);
+}
+
+void GC::pause() {
+  this->pauseCount++;
+}
+
+void GC::resume() {
+  this->pauseCount--;
+}
+
+std::size_t GC::liftPause() {
+  auto count = this->pauseCount;
+  this->pauseCount = 0;
+  return count;
+}
+
+void GC::restorePause(std::size_t count) {
+  this->pauseCount = count;
+}
+
+void GC::setLog(bool log) {
+  this->log = log;
+}
+
+std::size_t GC::heapBytes() {
+  return this->nurseryHeap + this->retirementHomeHeap;
+}
+
+std::size_t GC::objectCount() {
+  return this->objects;
+}
+
+GCStats &GC::getStats() {
+  return this->stats;
 }
 
 Value GC::createString(std::string value) {
   auto str = new String(value);
   this->addObject(str);
-  this->nurseryHeap += sizeof(String);
   return str;
 }
 
 Value GC::createAtom(std::string value) {
   auto atom = new Atom(value);
   this->addObject(atom);
-  this->nurseryHeap += sizeof(Atom);
   return atom;
 }
 
 Value GC::createList(std::vector<Value> elements) {
   auto list = new List(elements);
   this->addObject(list);
-  this->nurseryHeap += sizeof(List);
   return list;
 }
 
-Value GC::createTable(std::unordered_map<std::string, Value> hashMap) {
+Value GC::createTable(OrderedMap hashMap) {
   auto table = new Table(hashMap);
   this->addObject(table);
-  this->nurseryHeap += sizeof(Table);
   return table;
 }
 
 Value GC::createTuple(std::vector<Value> values) {
   auto tuple = new Tuple(values);
   this->addObject(tuple);
-  this->nurseryHeap += sizeof(Tuple);
   return tuple;
 }
 
-Value GC::createFunction(std::string name,
-                         std::uint16_t arity,
-                         std::uint8_t *buffers) {
-  auto func = new Function(name, arity, buffers);
+Value GC::createNativeFunction(std::string name,
+                               std::uint16_t arity,
+                               NativeFn fn,
+                               std::vector<Value> data) {
+  auto native = new NativeFunction(name, arity, fn, data);
+  this->addObject(native);
+  return native;
+}
+
+Value GC::createError(std::string message, Value cause) {
+  auto error = new Error(message, cause);
+  this->addObject(error);
+  return error;
+}
+
+Value GC::createDecimal(std::int64_t units, std::uint8_t scale) {
+  auto decimal = new Decimal(units, scale);
+  this->addObject(decimal);
+  return decimal;
+}
+
+Value GC::createFunction(std::string name, std::uint16_t arity, Chunk chunk) {
+  auto func = new Function(name, arity, chunk);
   this->addObject(func);
-  this->nurseryHeap += sizeof(Function);
   return func;
 }
 
+Value GC::createUpvalue(std::size_t slot) {
+  auto upvalue = new Upvalue(slot);
+  this->addObject(upvalue);
+  return upvalue;
+}
+
 bool Value::truthy() {
-  if (std::holds_alternative<std::int64_t>(this->value)) {
+  if (std::holds_alternative<char>(this->value)) {
+    return false;
+  } else if (std::holds_alternative<std::int64_t>(this->value)) {
     auto v = std::get<std::int64_t>(this->value);
     return v != 0;
   } else if (std::holds_alternative<double>(this->value)) {
@@ -136,16 +330,23 @@ std::string Value::toString() {
   } else if (std::holds_alternative<std::int64_t>(this->value)) {
     return std::to_string(std::get<std::int64_t>(this->value));
   } else if (std::holds_alternative<double>(this->value)) {
-    return std::to_string(std::get<double>(this->value));
+    // Shortest form that reads back as the same float, keeping a ".0" so it
+    // never looks like an integer
+    char buf[32];
+    auto result =
+        std::to_chars(buf, buf + sizeof(buf), std::get<double>(this->value));
+    std::string s(buf, result.ptr);
+    if (s.find_first_of(".ein") == std::string::npos) s += ".0";
+    return s;
   } else if (std::holds_alternative<bool>(this->value)) {
-    return std::to_string(std::get<bool>(this->value));
+    return std::get<bool>(this->value) ? "true" : "false";
   } else if (std::holds_alternative<Object *>(this->value)) {
     auto obj = std::get<Object *>(this->value);
-    if (typeid(obj) == typeid(String))
+    if (typeid(*obj) == typeid(String))
       return static_cast<String *>(obj)->value;
-    else if (typeid(obj) == typeid(Atom))
+    else if (typeid(*obj) == typeid(Atom))
       return static_cast<Atom *>(obj)->value;
-    else if (typeid(obj) == typeid(List)) {
+    else if (typeid(*obj) == typeid(List)) {
       auto list = static_cast<List *>(obj);
       std::string s{"["};
       for (std::uint32_t i = 0; i < list->elements.size(); i++) {
@@ -154,18 +355,18 @@ std::string Value::toString() {
       }
       s += "]";
       return s;
-    } else if (typeid(obj) == typeid(Table)) {
+    } else if (typeid(*obj) == typeid(Table)) {
       auto table = static_cast<Table *>(obj);
       std::string s{"{"};
       std::size_t count = 0;
       for (auto &pair : table->hashMap) {
         count++;
         s += pair.first + ": " + pair.second.toString();
-        if (count + 1 != table->hashMap.size()) s += ", ";
+        if (count != table->hashMap.size()) s += ", ";
       }
       s += "}";
       return s;
-    } else if (typeid(obj) == typeid(Tuple)) {
+    } else if (typeid(*obj) == typeid(Tuple)) {
       auto tuple = static_cast<Tuple *>(obj);
       std::string s{"<"};
       for (std::uint32_t i = 0; i < tuple->values.size(); i++) {
@@ -174,9 +375,26 @@ std::string Value::toString() {
       }
       s += ">";
       return s;
-    } else if (typeid(obj) == typeid(Function)) {
+    } else if (typeid(*obj) == typeid(Function)) {
       auto func = static_cast<Function *>(obj);
       return "<function " + func->name + ">";
+    } else if (typeid(*obj) == typeid(NativeFunction)) {
+      auto native = static_cast<NativeFunction *>(obj);
+      return "<native function " + native->name + ">";
+    } else if (typeid(*obj) == typeid(Decimal)) {
+      auto decimal = static_cast<Decimal *>(obj);
+      // The magnitude is unsigned so that INT64_MIN has one
+      auto magnitude = decimal->units < 0
+                           ? 0 - static_cast<std::uint64_t>(decimal->units)
+                           : static_cast<std::uint64_t>(decimal->units);
+      auto digits = std::to_string(magnitude);
+      if (digits.size() <= decimal->scale)
+        digits.insert(0, decimal->scale - digits.size() + 1, '0');
+      if (decimal->scale > 0)
+        digits.insert(digits.size() - decimal->scale, ".");
+      return (decimal->units < 0 ? "-" : "") + digits;
+    } else if (typeid(*obj) == typeid(Error)) {
+      return "<error " + static_cast<Error *>(obj)->message + ">";
     }
   }
 
@@ -184,9 +402,9 @@ std::string Value::toString() {
 }
 
 std::string Value::toDbgString() {
-  if (!std::holds_alternative<Object *>(this->value)) {
+  if (std::holds_alternative<Object *>(this->value)) {
     auto obj = std::get<Object *>(this->value);
-    if (typeid(obj) == typeid(List)) {
+    if (typeid(*obj) == typeid(List)) {
       auto list = static_cast<List *>(obj);
       std::string s{"["};
       for (std::uint32_t i = 0; i < list->elements.size(); i++) {
@@ -195,18 +413,18 @@ std::string Value::toDbgString() {
       }
       s += "]";
       return s;
-    } else if (typeid(obj) == typeid(Table)) {
+    } else if (typeid(*obj) == typeid(Table)) {
       auto table = static_cast<Table *>(obj);
       std::string s{"{"};
       std::size_t count = 0;
       for (auto &pair : table->hashMap) {
         count++;
         s += pair.first + ": " + pair.second.toDbgString();
-        if (count + 1 != table->hashMap.size()) s += ", ";
+        if (count != table->hashMap.size()) s += ", ";
       }
       s += "}";
       return s;
-    } else if (typeid(obj) == typeid(Tuple)) {
+    } else if (typeid(*obj) == typeid(Tuple)) {
       auto tuple = static_cast<Tuple *>(obj);
       std::string s{"<"};
       for (std::uint32_t i = 0; i < tuple->values.size(); i++) {
@@ -215,9 +433,9 @@ std::string Value::toDbgString() {
       }
       s += ">";
       return s;
-    } else if (typeid(obj) != typeid(String))
-      return this->toString();
+    } else if (typeid(*obj) == typeid(String))
+      return "'" + this->toString() + "'";
   }
 
-  return "'" + this->toString() + "'";
+  return this->toString();
 }
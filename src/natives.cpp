@@ -0,0 +1,1795 @@
+#include "natives.hpp"
+
+#ifdef FLAN_PLUGINS
+#include <dlfcn.h>
+#endif
+
+#include <algorithm>
+#include <charconv>
+#include <chrono>
+#include <cctype>
+#include <cmath>
+#include <cstring>
+#include <cstdlib>
+#include <iostream>
+#include <iterator>
+#include <sstream>
+#include <string>
+#include <system_error>
+#include <thread>
+#include <typeinfo>
+#include <unordered_map>
+#include <utility>
+#include <variant>
+#include <vector>
+
+#include "plugin.hpp"
+#include "vm.hpp"
+
+using namespace flan;
+
+static Value ioReadLine(VM &vm, std::vector<Value> &) {
+  std::string line;
+  if (!std::getline(std::cin, line)) return Value();
+  return vm.getGC().createString(line);
+}
+
+static Value ioWrite(VM &, std::vector<Value> &args) {
+  std::cout << args[0].toString();
+  return Value();
+}
+
+static Value ioWriteErr(VM &, std::vector<Value> &args) {
+  std::cerr << args[0].toString();
+  return Value();
+}
+
+static Value ioFlush(VM &, std::vector<Value> &) {
+  std::cout.flush();
+  return Value();
+}
+
+static Value ioSetBuffered(VM &, std::vector<Value> &args) {
+  if (args[0].truthy())
+    std::cout << std::nounitbuf;
+  else
+    std::cout << std::unitbuf;
+  return Value();
+}
+
+static void defineIO(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap io;
+  io["read_line"] = gc.createNativeFunction("read_line", 0, ioReadLine);
+  io["write"] = gc.createNativeFunction("write", 1, ioWrite);
+  io["write_err"] = gc.createNativeFunction("write_err", 1, ioWriteErr);
+  io["flush"] = gc.createNativeFunction("flush", 0, ioFlush);
+  io["set_buffered"] =
+      gc.createNativeFunction("set_buffered", 1, ioSetBuffered);
+  vm.defineGlobal("io", gc.createTable(io));
+}
+
+#ifndef FLAN_NO_OS
+static Value osExit(VM &vm, std::vector<Value> &args) {
+  if (!std::holds_alternative<std::int64_t>(args[0].value))
+    vm.nativeError("Expected an integer exit status but got " +
+                   args[0].toDbgString());
+  std::cout.flush();
+  std::exit(static_cast<int>(std::get<std::int64_t>(args[0].value)));
+}
+
+static void defineOS(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap os;
+  os["exit"] = gc.createNativeFunction("exit", 1, osExit);
+  vm.defineGlobal("os", gc.createTable(os));
+}
+#endif
+
+static Value runtimeHeapBytes(VM &vm, std::vector<Value> &) {
+  return static_cast<std::int64_t>(vm.getGC().heapBytes());
+}
+
+static Value runtimeObjectCount(VM &vm, std::vector<Value> &) {
+  return static_cast<std::int64_t>(vm.getGC().objectCount());
+}
+
+static Value runtimeGC(VM &vm, std::vector<Value> &) {
+  vm.getGC().collect();
+  return Value();
+}
+
+static Value runtimeGCStats(VM &vm, std::vector<Value> &) {
+  auto &gc = vm.getGC();
+  auto &stats = gc.getStats();
+  OrderedMap table;
+  table["nursery_collections"] =
+      static_cast<std::int64_t>(stats.nurseryCollections);
+  table["full_collections"] = static_cast<std::int64_t>(stats.fullCollections);
+  table["freed_objects"] = static_cast<std::int64_t>(stats.freedObjects);
+  table["freed_bytes"] = static_cast<std::int64_t>(stats.freedBytes);
+  return gc.createTable(table);
+}
+
+static Value runtimeAtomStats(VM &vm, std::vector<Value> &) {
+  auto &stats = vm.getAtomStats();
+  OrderedMap table;
+  table["hits"] = static_cast<std::int64_t>(stats.hits);
+  table["misses"] = static_cast<std::int64_t>(stats.misses);
+  return vm.getGC().createTable(table);
+}
+
+static Value runtimeGlobals(VM &vm, std::vector<Value> &) {
+  auto &gc = vm.getGC();
+  std::vector<Value> names;
+  for (auto &name : vm.globalNames()) names.push_back(gc.createString(name));
+  return gc.createList(std::move(names));
+}
+
+static Table *expectTable(VM &vm, Value value) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Table)) return static_cast<Table *>(obj);
+  }
+  vm.nativeError("Expected an object but got " + value.toDbgString());
+}
+
+// The keys of a module table, in the order the module defines them
+static Value runtimeExports(VM &vm, std::vector<Value> &args) {
+  auto &gc = vm.getGC();
+  std::vector<Value> keys;
+  for (auto &pair : expectTable(vm, args[0])->hashMap)
+    keys.push_back(gc.createString(pair.first));
+  return gc.createList(std::move(keys));
+}
+
+static void defineRuntime(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap runtime;
+  runtime["heap_bytes"] =
+      gc.createNativeFunction("heap_bytes", 0, runtimeHeapBytes);
+  runtime["object_count"] =
+      gc.createNativeFunction("object_count", 0, runtimeObjectCount);
+  runtime["gc"] = gc.createNativeFunction("gc", 0, runtimeGC);
+  runtime["gc_stats"] = gc.createNativeFunction("gc_stats", 0, runtimeGCStats);
+  runtime["atom_stats"] =
+      gc.createNativeFunction("atom_stats", 0, runtimeAtomStats);
+  runtime["globals"] = gc.createNativeFunction("globals", 0, runtimeGlobals);
+  runtime["exports"] = gc.createNativeFunction("exports", 1, runtimeExports);
+  vm.defineGlobal("runtime", gc.createTable(runtime));
+}
+
+static std::string expectString(VM &vm, Value value, std::string what) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(String))
+      return static_cast<String *>(obj)->value;
+  }
+  vm.nativeError("Expected a string " + what + " but got " +
+                 value.toDbgString());
+}
+
+static std::int64_t expectInt(VM &vm, Value value, std::string what) {
+  if (!std::holds_alternative<std::int64_t>(value.value))
+    vm.nativeError("Expected an integer " + what + " but got " +
+                   value.toDbgString());
+  return std::get<std::int64_t>(value.value);
+}
+
+static Table *expectOptions(VM &vm, Value value) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Table)) return static_cast<Table *>(obj);
+  }
+  vm.nativeError("Expected an options table but got " + value.toDbgString());
+}
+
+static std::int64_t expectBase(VM &vm, Value value) {
+  auto base = expectInt(vm, value, "base");
+  if (base < 2 || base > 36)
+    vm.nativeError("Base must be between 2 and 36 but got " +
+                   std::to_string(base));
+  return base;
+}
+
+// Parsing goes through std::from_chars, which ignores the C locale and
+// accepts no surrounding whitespace. Malformed input yields _ rather than an
+// error so that callers can validate user input.
+static Value numericInt(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to parse");
+  auto base = expectBase(vm, args[1]);
+
+  std::int64_t result;
+  auto first = str.data();
+  auto last = str.data() + str.size();
+  auto [ptr, ec] = std::from_chars(first, last, result, base);
+  if (ec != std::errc() || ptr != last || first == last) return Value();
+  return result;
+}
+
+static Value numericFloat(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to parse");
+
+  double result;
+  auto first = str.data();
+  auto last = str.data() + str.size();
+  auto [ptr, ec] = std::from_chars(first, last, result);
+  if (ec != std::errc() || ptr != last || first == last) return Value();
+  return result;
+}
+
+// Options: base (default 10), pad (minimum digit count, zero-filled) and sep
+// (inserted between every three digits)
+static Value numericFormatInt(VM &vm, std::vector<Value> &args) {
+  auto n = expectInt(vm, args[0], "to format");
+  auto options = expectOptions(vm, args[1])->hashMap;
+
+  std::int64_t base = 10;
+  if (options.count("base")) base = expectBase(vm, options["base"]);
+  std::int64_t pad = 0;
+  if (options.count("pad")) pad = expectInt(vm, options["pad"], "pad");
+  std::string sep;
+  if (options.count("sep")) sep = expectString(vm, options["sep"], "sep");
+
+  // Large enough for a negative 64-bit integer in base 2
+  char buf[66];
+  auto result =
+      std::to_chars(buf, buf + sizeof(buf), n, static_cast<int>(base));
+  std::string digits(buf, result.ptr);
+
+  std::string sign;
+  if (n < 0) {
+    sign = "-";
+    digits.erase(0, 1);
+  }
+
+  if (static_cast<std::int64_t>(digits.size()) < pad)
+    digits.insert(0, pad - digits.size(), '0');
+
+  if (!sep.empty())
+    for (auto i = static_cast<std::int64_t>(digits.size()) - 3; i > 0; i -= 3)
+      digits.insert(i, sep);
+
+  return vm.getGC().createString(sign + digits);
+}
+
+// Options: precision (digits after the point; shortest round-trip form when
+// absent) and sci (use scientific notation)
+static Value numericFormatFloat(VM &vm, std::vector<Value> &args) {
+  if (!std::holds_alternative<double>(args[0].value))
+    vm.nativeError("Expected a float to format but got " +
+                   args[0].toDbgString());
+  auto f = std::get<double>(args[0].value);
+  auto options = expectOptions(vm, args[1])->hashMap;
+
+  auto format = std::chars_format::fixed;
+  if (options.count("sci") && options["sci"].truthy())
+    format = std::chars_format::scientific;
+
+  char buf[512];
+  std::to_chars_result result;
+  if (options.count("precision")) {
+    auto precision = expectInt(vm, options["precision"], "precision");
+    if (precision < 0 || precision > 100)
+      vm.nativeError("Precision must be between 0 and 100 but got " +
+                     std::to_string(precision));
+    result = std::to_chars(buf, buf + sizeof(buf), f, format,
+                           static_cast<int>(precision));
+  } else {
+    result = std::to_chars(buf, buf + sizeof(buf), f, format);
+  }
+
+  if (result.ec != std::errc()) vm.nativeError("Float is too long to format");
+  return vm.getGC().createString(std::string(buf, result.ptr));
+}
+
+static Value mathIsNaN(VM &, std::vector<Value> &args) {
+  if (!std::holds_alternative<double>(args[0].value)) return false;
+  return std::isnan(std::get<double>(args[0].value));
+}
+
+static Value mathIsInf(VM &, std::vector<Value> &args) {
+  if (!std::holds_alternative<double>(args[0].value)) return false;
+  return std::isinf(std::get<double>(args[0].value));
+}
+
+static void defineMath(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap math;
+  math["nan?"] = gc.createNativeFunction("nan?", 1, mathIsNaN);
+  math["inf?"] = gc.createNativeFunction("inf?", 1, mathIsInf);
+  vm.defineGlobal("math", gc.createTable(math));
+}
+
+static std::string expectAtom(VM &vm, Value value, std::string what) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Atom)) return static_cast<Atom *>(obj)->value;
+  }
+  vm.nativeError("Expected an atom " + what + " but got " +
+                 value.toDbgString());
+}
+
+// Modes are :floor, :ceil, :round (halves away from zero) and :trunc
+static Value conversionToInt(VM &vm, std::vector<Value> &args) {
+  if (std::holds_alternative<std::int64_t>(args[0].value)) return args[0];
+  if (!std::holds_alternative<double>(args[0].value))
+    vm.nativeError("Expected a float to convert but got " +
+                   args[0].toDbgString());
+
+  auto f = std::get<double>(args[0].value);
+  auto mode = expectAtom(vm, args[1], "rounding mode");
+  if (mode == "floor")
+    f = std::floor(f);
+  else if (mode == "ceil")
+    f = std::ceil(f);
+  else if (mode == "round")
+    f = std::round(f);
+  else if (mode == "trunc")
+    f = std::trunc(f);
+  else
+    vm.nativeError("Unknown rounding mode :" + mode);
+
+  // 2^63 is exact as a double, unlike INT64_MAX
+  if (!(f >= -9223372036854775808.0 && f < 9223372036854775808.0))
+    vm.nativeError(args[0].toString() + " does not fit in an integer");
+  return static_cast<std::int64_t>(f);
+}
+
+static Value conversionToFloat(VM &vm, std::vector<Value> &args) {
+  if (std::holds_alternative<double>(args[0].value)) return args[0];
+  if (std::holds_alternative<std::int64_t>(args[0].value))
+    return static_cast<double>(std::get<std::int64_t>(args[0].value));
+
+  if (std::holds_alternative<Object *>(args[0].value)) {
+    auto obj = std::get<Object *>(args[0].value);
+    if (typeid(*obj) == typeid(Decimal)) {
+      auto decimal = static_cast<Decimal *>(obj);
+      return static_cast<double>(decimal->units) /
+             std::pow(10.0, decimal->scale);
+    }
+  }
+
+  vm.nativeError("Expected a number to convert but got " +
+                 args[0].toDbgString());
+}
+
+static bool hasPrefix(std::string &str, std::string &prefix) {
+  return str.compare(0, prefix.size(), prefix) == 0;
+}
+
+static bool hasSuffix(std::string &str, std::string &suffix) {
+  return str.size() >= suffix.size() &&
+         str.compare(str.size() - suffix.size(), suffix.size(), suffix) == 0;
+}
+
+static Value strStartsWith(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to test");
+  auto prefix = expectString(vm, args[1], "prefix");
+  return hasPrefix(str, prefix);
+}
+
+static Value strEndsWith(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to test");
+  auto suffix = expectString(vm, args[1], "suffix");
+  return hasSuffix(str, suffix);
+}
+
+// The strip functions return _ when the string does not match, so a prefix
+// or suffix pattern compiles to one call and a Jz; an empty rest is still
+// truthy
+static Value strStripPrefix(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to strip");
+  auto prefix = expectString(vm, args[1], "prefix");
+  if (!hasPrefix(str, prefix)) return Value();
+  return vm.getGC().createString(str.substr(prefix.size()));
+}
+
+static Value strStripSuffix(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to strip");
+  auto suffix = expectString(vm, args[1], "suffix");
+  if (!hasSuffix(str, suffix)) return Value();
+  return vm.getGC().createString(str.substr(0, str.size() - suffix.size()));
+}
+
+static void defineStr(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap str;
+  str["starts_with?"] =
+      gc.createNativeFunction("starts_with?", 2, strStartsWith);
+  str["ends_with?"] = gc.createNativeFunction("ends_with?", 2, strEndsWith);
+  str["strip_prefix"] =
+      gc.createNativeFunction("strip_prefix", 2, strStripPrefix);
+  str["strip_suffix"] =
+      gc.createNativeFunction("strip_suffix", 2, strStripSuffix);
+  vm.defineGlobal("str", gc.createTable(str));
+}
+
+// Both binary functions return _ when the field runs past the end of the
+// string, which is how a compiled byte-string pattern fails to match
+static Value binaryUInt(VM &vm, std::vector<Value> &args) {
+  auto bytes = expectString(vm, args[0], "of bytes");
+  auto offset = expectInt(vm, args[1], "offset");
+  auto size = expectInt(vm, args[2], "size");
+  auto order = expectAtom(vm, args[3], "byte order");
+  if (size < 1 || size > 8)
+    vm.nativeError("Size must be between 1 and 8 bytes but got " +
+                   std::to_string(size));
+  if (order != "big" && order != "little")
+    vm.nativeError("Byte order must be :big or :little but got :" + order);
+
+  if (offset < 0 || static_cast<std::uint64_t>(offset) > bytes.size() ||
+      bytes.size() - offset < static_cast<std::uint64_t>(size))
+    return Value();
+
+  // Eight-byte fields wrap around into negative integers
+  std::uint64_t result = 0;
+  for (std::int64_t i = 0; i < size; i++) {
+    auto byte = static_cast<std::uint8_t>(
+        bytes[offset + (order == "big" ? i : size - 1 - i)]);
+    result = (result << 8) | byte;
+  }
+  return static_cast<std::int64_t>(result);
+}
+
+static Value binarySlice(VM &vm, std::vector<Value> &args) {
+  auto bytes = expectString(vm, args[0], "of bytes");
+  auto offset = expectInt(vm, args[1], "offset");
+  auto length = expectInt(vm, args[2], "length");
+
+  if (offset < 0 || length < 0 ||
+      static_cast<std::uint64_t>(offset) > bytes.size() ||
+      bytes.size() - offset < static_cast<std::uint64_t>(length))
+    return Value();
+  return vm.getGC().createString(bytes.substr(offset, length));
+}
+
+static void defineBinary(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap binary;
+  binary["uint"] = gc.createNativeFunction("uint", 4, binaryUInt);
+  binary["slice"] = gc.createNativeFunction("slice", 3, binarySlice);
+  vm.defineGlobal("binary", gc.createTable(binary));
+}
+
+#ifndef FLAN_NO_DECIMAL
+// Decimals are fixed point with at most this many digits after the point,
+// so that every power of ten involved fits in an int64
+const std::uint8_t MAX_DECIMAL_SCALE = 18;
+
+static const std::int64_t POWERS_OF_TEN[] = {
+    1,
+    10,
+    100,
+    1000,
+    10000,
+    100000,
+    1000000,
+    10000000,
+    100000000,
+    1000000000,
+    10000000000,
+    100000000000,
+    1000000000000,
+    10000000000000,
+    100000000000000,
+    1000000000000000,
+    10000000000000000,
+    100000000000000000,
+    1000000000000000000};
+
+struct DecimalValue {
+  std::int64_t units;
+  std::uint8_t scale;
+};
+
+// Integers are accepted wherever a decimal is, as a decimal of scale 0
+static DecimalValue expectDecimal(VM &vm, Value value) {
+  if (std::holds_alternative<std::int64_t>(value.value))
+    return {std::get<std::int64_t>(value.value), 0};
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Decimal)) {
+      auto decimal = static_cast<Decimal *>(obj);
+      return {decimal->units, decimal->scale};
+    }
+  }
+  vm.nativeError("Expected a decimal but got " + value.toDbgString());
+}
+
+static std::uint8_t expectPlaces(VM &vm, Value value) {
+  auto places = expectInt(vm, value, "number of places");
+  if (places < 0 || places > MAX_DECIMAL_SCALE)
+    vm.nativeError("Number of places must be between 0 and 18 but got " +
+                   std::to_string(places));
+  return static_cast<std::uint8_t>(places);
+}
+
+static Value createDecimal(VM &vm, DecimalValue d) {
+  return vm.getGC().createDecimal(d.units, d.scale);
+}
+
+static DecimalValue rescale(VM &vm, DecimalValue d, std::uint8_t scale) {
+  std::int64_t units;
+  if (__builtin_mul_overflow(d.units, POWERS_OF_TEN[scale - d.scale], &units))
+    vm.nativeError("Decimal overflow");
+  return {units, scale};
+}
+
+// Divides and rounds halves to even, the usual rule for money
+static std::int64_t divideHalfEven(std::int64_t n, std::int64_t d) {
+  auto q = n / d;
+  auto r = n % d;
+  if (r == 0) return q;
+
+  // Compared without doubling the remainder, which could overflow
+  std::uint64_t absR = r < 0 ? 0 - static_cast<std::uint64_t>(r) : r;
+  std::uint64_t absD = d < 0 ? 0 - static_cast<std::uint64_t>(d) : d;
+  auto rest = absD - absR;
+  if (absR > rest || (absR == rest && q % 2 != 0))
+    q += ((n < 0) != (d < 0)) ? -1 : 1;
+  return q;
+}
+
+static DecimalValue roundTo(VM &vm, DecimalValue d, std::uint8_t places) {
+  if (places >= d.scale) return rescale(vm, d, places);
+  return {divideHalfEven(d.units, POWERS_OF_TEN[d.scale - places]), places};
+}
+
+// Accepts an optional sign, digits and an optional fractional part, and
+// returns _ for anything else
+static Value decimalParse(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to parse");
+
+  std::size_t i = 0;
+  bool negative = false;
+  if (i < str.size() && (str[i] == '-' || str[i] == '+'))
+    negative = str[i++] == '-';
+
+  std::int64_t units = 0;
+  std::uint8_t scale = 0;
+  bool seenDigit = false;
+  bool seenPoint = false;
+  for (; i < str.size(); i++) {
+    if (str[i] == '.' && !seenPoint) {
+      seenPoint = true;
+      continue;
+    }
+    if (str[i] < '0' || str[i] > '9') return Value();
+    if (seenPoint && ++scale > MAX_DECIMAL_SCALE) return Value();
+
+    seenDigit = true;
+    std::int64_t digit = str[i] - '0';
+    if (__builtin_mul_overflow(units, 10, &units) ||
+        __builtin_add_overflow(units, negative ? -digit : digit, &units))
+      return Value();
+  }
+
+  if (!seenDigit) return Value();
+  return vm.getGC().createDecimal(units, scale);
+}
+
+static Value decimalAdd(VM &vm, std::vector<Value> &args) {
+  auto a = expectDecimal(vm, args[0]);
+  auto b = expectDecimal(vm, args[1]);
+  auto scale = std::max(a.scale, b.scale);
+  a = rescale(vm, a, scale);
+  b = rescale(vm, b, scale);
+
+  std::int64_t units;
+  if (__builtin_add_overflow(a.units, b.units, &units))
+    vm.nativeError("Decimal overflow");
+  return createDecimal(vm, {units, scale});
+}
+
+static Value decimalSub(VM &vm, std::vector<Value> &args) {
+  auto a = expectDecimal(vm, args[0]);
+  auto b = expectDecimal(vm, args[1]);
+  auto scale = std::max(a.scale, b.scale);
+  a = rescale(vm, a, scale);
+  b = rescale(vm, b, scale);
+
+  std::int64_t units;
+  if (__builtin_sub_overflow(a.units, b.units, &units))
+    vm.nativeError("Decimal overflow");
+  return createDecimal(vm, {units, scale});
+}
+
+static Value decimalMul(VM &vm, std::vector<Value> &args) {
+  auto a = expectDecimal(vm, args[0]);
+  auto b = expectDecimal(vm, args[1]);
+
+  std::int64_t units;
+  if (__builtin_mul_overflow(a.units, b.units, &units))
+    vm.nativeError("Decimal overflow");
+
+  auto product = DecimalValue{units, 0};
+  auto scale = a.scale + b.scale;
+  if (scale > MAX_DECIMAL_SCALE) {
+    product.units =
+        divideHalfEven(units, POWERS_OF_TEN[scale - MAX_DECIMAL_SCALE]);
+    scale = MAX_DECIMAL_SCALE;
+  }
+  product.scale = static_cast<std::uint8_t>(scale);
+  return createDecimal(vm, product);
+}
+
+// The quotient is rounded half to even at the requested number of places
+static Value decimalDiv(VM &vm, std::vector<Value> &args) {
+  auto a = expectDecimal(vm, args[0]);
+  auto b = expectDecimal(vm, args[1]);
+  auto places = expectPlaces(vm, args[2]);
+  if (b.units == 0) vm.nativeError("Cannot divide by zero");
+
+  // a / b at `places` is (a.units * 10^shift) / b.units with
+  // shift = places + b.scale - a.scale
+  auto n = a.units;
+  auto d = b.units;
+  auto shift = places + b.scale - a.scale;
+  if (shift > MAX_DECIMAL_SCALE || -shift > MAX_DECIMAL_SCALE)
+    vm.nativeError("Decimal overflow");
+  if (shift >= 0 && __builtin_mul_overflow(n, POWERS_OF_TEN[shift], &n))
+    vm.nativeError("Decimal overflow");
+  if (shift < 0 && __builtin_mul_overflow(d, POWERS_OF_TEN[-shift], &d))
+    vm.nativeError("Decimal overflow");
+  if (n == INT64_MIN && d == -1) vm.nativeError("Decimal overflow");
+
+  return createDecimal(vm, {divideHalfEven(n, d), places});
+}
+
+static Value decimalRound(VM &vm, std::vector<Value> &args) {
+  auto d = expectDecimal(vm, args[0]);
+  return createDecimal(vm, roundTo(vm, d, expectPlaces(vm, args[1])));
+}
+
+// Returns -1, 0 or 1
+static Value decimalCmp(VM &vm, std::vector<Value> &args) {
+  auto a = expectDecimal(vm, args[0]);
+  auto b = expectDecimal(vm, args[1]);
+  auto scale = std::max(a.scale, b.scale);
+  a = rescale(vm, a, scale);
+  b = rescale(vm, b, scale);
+  return static_cast<std::int64_t>((a.units > b.units) - (a.units < b.units));
+}
+
+static void defineDecimal(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap decimal;
+  decimal["parse"] = gc.createNativeFunction("parse", 1, decimalParse);
+  decimal["add"] = gc.createNativeFunction("add", 2, decimalAdd);
+  decimal["sub"] = gc.createNativeFunction("sub", 2, decimalSub);
+  decimal["mul"] = gc.createNativeFunction("mul", 2, decimalMul);
+  decimal["div"] = gc.createNativeFunction("div", 3, decimalDiv);
+  decimal["round"] = gc.createNativeFunction("round", 2, decimalRound);
+  decimal["cmp"] = gc.createNativeFunction("cmp", 2, decimalCmp);
+  vm.defineGlobal("decimal", gc.createTable(decimal));
+}
+#endif
+
+static Value conversionBool(VM &, std::vector<Value> &args) {
+  return args[0].truthy();
+}
+
+static const char *typeName(Value value) {
+  if (std::holds_alternative<char>(value.value)) return "null";
+  if (std::holds_alternative<std::int64_t>(value.value)) return "int";
+  if (std::holds_alternative<double>(value.value)) return "float";
+  if (std::holds_alternative<bool>(value.value)) return "bool";
+
+  auto obj = std::get<Object *>(value.value);
+  if (typeid(*obj) == typeid(String)) return "string";
+  if (typeid(*obj) == typeid(Atom)) return "atom";
+  if (typeid(*obj) == typeid(List)) return "list";
+  if (typeid(*obj) == typeid(Table)) return "object";
+  if (typeid(*obj) == typeid(Tuple)) return "tuple";
+  if (typeid(*obj) == typeid(Function) ||
+      typeid(*obj) == typeid(NativeFunction))
+    return "func";
+  if (typeid(*obj) == typeid(Error)) return "error";
+  if (typeid(*obj) == typeid(Decimal)) return "decimal";
+  return "unknown";
+}
+
+static Value typeType(VM &vm, std::vector<Value> &args) {
+  return vm.getGC().createAtom(typeName(args[0]));
+}
+
+// Natives carry no data of their own, so each predicate is an instantiation
+// that compares against one entry of this list
+static const char *PREDICATE_TYPES[] = {
+    "null",   "int",   "float", "bool", "string", "atom",
+    "list",   "object", "tuple", "func", "error",  "decimal"};
+
+template <std::size_t I>
+static Value typeIs(VM &, std::vector<Value> &args) {
+  return std::string(typeName(args[0])) == PREDICATE_TYPES[I];
+}
+
+template <std::size_t... I>
+static void definePredicates(VM &vm, std::index_sequence<I...>) {
+  auto &gc = vm.getGC();
+  (vm.defineGlobal(std::string(PREDICATE_TYPES[I]) + "?",
+                   gc.createNativeFunction(
+                       std::string(PREDICATE_TYPES[I]) + "?", 1, typeIs<I>)),
+   ...);
+}
+
+static void defineTypes(VM &vm) {
+  vm.defineGlobal("type",
+                  vm.getGC().createNativeFunction("type", 1, typeType));
+  definePredicates(
+      vm, std::make_index_sequence<std::size(PREDICATE_TYPES)>());
+}
+
+// Orders two values the way `<` and `>` do, as :lt, :eq or :gt. NaN has no
+// order, so it is an error here rather than comparing false.
+static Value comparisonCmp(VM &vm, std::vector<Value> &args) {
+  auto &a = args[0];
+  auto &b = args[1];
+  int order = 0;
+  bool ordered = false;
+
+  if (std::holds_alternative<std::int64_t>(a.value) &&
+      std::holds_alternative<std::int64_t>(b.value)) {
+    auto l = std::get<std::int64_t>(a.value);
+    auto r = std::get<std::int64_t>(b.value);
+    order = (l > r) - (l < r);
+    ordered = true;
+  } else if ((std::holds_alternative<std::int64_t>(a.value) ||
+              std::holds_alternative<double>(a.value)) &&
+             (std::holds_alternative<std::int64_t>(b.value) ||
+              std::holds_alternative<double>(b.value))) {
+    auto toDouble = [](Value &v) {
+      return std::holds_alternative<double>(v.value)
+                 ? std::get<double>(v.value)
+                 : static_cast<double>(std::get<std::int64_t>(v.value));
+    };
+    auto l = toDouble(a);
+    auto r = toDouble(b);
+    order = (l > r) - (l < r);
+    ordered = !std::isnan(l) && !std::isnan(r);
+  } else if (std::holds_alternative<Object *>(a.value) &&
+             std::holds_alternative<Object *>(b.value)) {
+    auto l = std::get<Object *>(a.value);
+    auto r = std::get<Object *>(b.value);
+    auto text = orderedText(l);
+    if (text != nullptr && typeid(*l) == typeid(*r)) {
+      auto compared = text->compare(*orderedText(r));
+      order = (compared > 0) - (compared < 0);
+      ordered = true;
+    }
+  }
+
+  if (!ordered)
+    vm.nativeError("Cannot compare " + a.toDbgString() + " and " +
+                   b.toDbgString());
+
+  static const char *const ORDERS[] = {"lt", "eq", "gt"};
+  return vm.getGC().createAtom(ORDERS[order + 1]);
+}
+
+static void defineComparisons(VM &vm) {
+  vm.defineGlobal("cmp",
+                  vm.getGC().createNativeFunction("cmp", 2, comparisonCmp));
+}
+
+// The folds below call back into Flan code once per element. Natives run with
+// the GC paused except inside those calls, so every list they build is
+// rooted before it grows.
+
+static List *expectList(VM &vm, Value value) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(List)) return static_cast<List *>(obj);
+  }
+  vm.nativeError("Expected a list but got " + value.toDbgString());
+}
+
+static List *rootedList(VM &vm) {
+  auto list = vm.getGC().createList({});
+  vm.root(list);
+  return static_cast<List *>(std::get<Object *>(list.value));
+}
+
+static Value call2(VM &vm, Value fn, Value a, Value b) {
+  std::vector<Value> callArgs{a, b};
+  return vm.call(fn, callArgs);
+}
+
+// Elements are read by index on every step, since the callback may grow or
+// shrink the list
+static Value foldFold(VM &vm, std::vector<Value> &args) {
+  auto list = expectList(vm, args[0]);
+  auto acc = args[1];
+  for (std::size_t i = 0; i < list->elements.size(); i++)
+    acc = call2(vm, args[2], acc, list->elements[i]);
+  return acc;
+}
+
+static Value foldReduce(VM &vm, std::vector<Value> &args) {
+  auto list = expectList(vm, args[0]);
+  if (list->elements.empty()) vm.nativeError("Cannot reduce an empty list");
+
+  auto acc = list->elements[0];
+  for (std::size_t i = 1; i < list->elements.size(); i++)
+    acc = call2(vm, args[1], acc, list->elements[i]);
+  return acc;
+}
+
+// Returns the accumulator after each element, without the initial value
+static Value foldScan(VM &vm, std::vector<Value> &args) {
+  auto list = expectList(vm, args[0]);
+  auto results = rootedList(vm);
+  auto acc = args[1];
+  for (std::size_t i = 0; i < list->elements.size(); i++) {
+    acc = call2(vm, args[2], acc, list->elements[i]);
+    results->elements.push_back(acc);
+  }
+  return results;
+}
+
+// fn(state) returns _ to stop, or a tuple of the next element and state
+static Value foldUnfold(VM &vm, std::vector<Value> &args) {
+  auto results = rootedList(vm);
+  auto state = args[0];
+  for (;;) {
+    std::vector<Value> callArgs{state};
+    auto step = vm.call(args[1], callArgs);
+    if (std::holds_alternative<char>(step.value)) break;
+
+    Tuple *tuple = nullptr;
+    if (std::holds_alternative<Object *>(step.value) &&
+        typeid(*std::get<Object *>(step.value)) == typeid(Tuple))
+      tuple = static_cast<Tuple *>(std::get<Object *>(step.value));
+    if (tuple == nullptr || tuple->values.size() != 2)
+      vm.nativeError("unfold expects _ or a tuple of two values but got " +
+                     step.toDbgString());
+
+    results->elements.push_back(tuple->values[0]);
+    state = tuple->values[1];
+  }
+  return results;
+}
+
+// Returns [seed, fn(seed), fn(fn(seed)), ...] with n elements
+static Value foldIterate(VM &vm, std::vector<Value> &args) {
+  auto n = expectInt(vm, args[2], "count");
+  if (n < 0)
+    vm.nativeError("Count must not be negative but got " + std::to_string(n));
+
+  auto results = rootedList(vm);
+  auto value = args[1];
+  for (std::int64_t i = 0; i < n; i++) {
+    results->elements.push_back(value);
+    if (i + 1 == n) break;
+    std::vector<Value> callArgs{value};
+    value = vm.call(args[0], callArgs);
+  }
+  return results;
+}
+
+static void defineFolds(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("fold", gc.createNativeFunction("fold", 3, foldFold));
+  vm.defineGlobal("reduce", gc.createNativeFunction("reduce", 2, foldReduce));
+  vm.defineGlobal("scan", gc.createNativeFunction("scan", 3, foldScan));
+  vm.defineGlobal("unfold", gc.createNativeFunction("unfold", 2, foldUnfold));
+  vm.defineGlobal("iterate",
+                  gc.createNativeFunction("iterate", 3, foldIterate));
+}
+
+static Value expectCallable(VM &vm, Value value, std::uint16_t &arity) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Function)) {
+      arity = static_cast<Function *>(obj)->arity;
+      return value;
+    } else if (typeid(*obj) == typeid(NativeFunction)) {
+      arity = static_cast<NativeFunction *>(obj)->arity;
+      return value;
+    }
+  }
+  vm.nativeError("Expected a function but got " + value.toDbgString());
+}
+
+static std::string functionName(Value value) {
+  auto obj = std::get<Object *>(value.value);
+  if (typeid(*obj) == typeid(Function))
+    return static_cast<Function *>(obj)->name;
+  return static_cast<NativeFunction *>(obj)->name;
+}
+
+static std::int64_t nowMillis() {
+  return std::chrono::duration_cast<std::chrono::milliseconds>(
+             std::chrono::steady_clock::now().time_since_epoch())
+      .count();
+}
+
+// Memoized calls are keyed by a serialization of their arguments. Strings,
+// numbers and collections are keyed by content, with table keys sorted so
+// that equal tables give equal keys; functions and errors by identity.
+const auto MAX_MEMO_KEY_DEPTH = 64;
+
+static void writeMemoKey(VM &vm, Value value, std::string &out, int depth) {
+  if (depth == MAX_MEMO_KEY_DEPTH)
+    vm.nativeError("Arguments are nested too deeply to memoize");
+
+  if (std::holds_alternative<char>(value.value)) {
+    out += "_";
+  } else if (std::holds_alternative<std::int64_t>(value.value)) {
+    out += "i" + std::to_string(std::get<std::int64_t>(value.value)) + ";";
+  } else if (std::holds_alternative<double>(value.value)) {
+    std::uint64_t bits;
+    auto f = std::get<double>(value.value);
+    std::memcpy(&bits, &f, sizeof(bits));
+    out += "f" + std::to_string(bits) + ";";
+  } else if (std::holds_alternative<bool>(value.value)) {
+    out += std::get<bool>(value.value) ? "T" : "F";
+  } else {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(String) || typeid(*obj) == typeid(Atom)) {
+      auto &str = typeid(*obj) == typeid(String)
+                      ? static_cast<String *>(obj)->value
+                      : static_cast<Atom *>(obj)->value;
+      out += (typeid(*obj) == typeid(String) ? "s" : "a") +
+             std::to_string(str.size()) + ":" + str;
+    } else if (typeid(*obj) == typeid(List) || typeid(*obj) == typeid(Tuple)) {
+      auto &values = typeid(*obj) == typeid(List)
+                         ? static_cast<List *>(obj)->elements
+                         : static_cast<Tuple *>(obj)->values;
+      out += (typeid(*obj) == typeid(List) ? "[" : "<");
+      for (auto &element : values) writeMemoKey(vm, element, out, depth + 1);
+      out += "]";
+    } else if (typeid(*obj) == typeid(Table)) {
+      auto &hashMap = static_cast<Table *>(obj)->hashMap;
+      std::vector<std::string> keys;
+      for (auto &pair : hashMap) keys.push_back(pair.first);
+      std::sort(keys.begin(), keys.end());
+
+      out += "{";
+      for (auto &key : keys) {
+        out += std::to_string(key.size()) + ":" + key;
+        writeMemoKey(vm, hashMap[key], out, depth + 1);
+      }
+      out += "}";
+    } else if (typeid(*obj) == typeid(Decimal)) {
+      auto decimal = static_cast<Decimal *>(obj);
+      out += "d" + std::to_string(decimal->units) + "," +
+             std::to_string(decimal->scale) + ";";
+    } else {
+      std::stringstream ss;
+      ss << "p" << static_cast<void *>(obj) << ";";
+      out += ss.str();
+    }
+  }
+}
+
+// Bound data: the wrapped function, the cache table mapping keys to
+// <result, expiry> tuples, the list of keys in insertion order, the maximum
+// number of entries and the time to live in milliseconds (0 for unlimited)
+static Value memoCall(VM &vm, std::vector<Value> &args) {
+  auto &data = vm.nativeData();
+  auto cache = static_cast<Table *>(std::get<Object *>(data[1].value));
+  auto order = static_cast<List *>(std::get<Object *>(data[2].value));
+  auto maxSize = std::get<std::int64_t>(data[3].value);
+  auto ttl = std::get<std::int64_t>(data[4].value);
+
+  std::string key;
+  for (auto &arg : args) writeMemoKey(vm, arg, key, 0);
+
+  auto entry = cache->hashMap.find(key);
+  if (entry != cache->hashMap.end()) {
+    auto tuple =
+        static_cast<Tuple *>(std::get<Object *>(entry->second.value));
+    auto expiry = std::get<std::int64_t>(tuple->values[1].value);
+    if (ttl == 0 || nowMillis() < expiry) return tuple->values[0];
+  }
+
+  auto result = vm.call(data[0], args);
+  vm.root(result);
+
+  auto &gc = vm.getGC();
+  if (!cache->hashMap.count(key)) {
+    if (maxSize > 0 &&
+        cache->hashMap.size() >= static_cast<std::size_t>(maxSize)) {
+      auto oldest = order->elements.front().toString();
+      order->elements.erase(order->elements.begin());
+      cache->hashMap.erase(oldest);
+    }
+    order->elements.push_back(gc.createString(key));
+  }
+  cache->hashMap[key] = gc.createTuple({result, nowMillis() + ttl});
+  return result;
+}
+
+static Value memoWrap(VM &vm, Value fn, std::int64_t size, std::int64_t ttl) {
+  std::uint16_t arity;
+  expectCallable(vm, fn, arity);
+  if (size < 0 || ttl < 0)
+    vm.nativeError("Memo size and ttl must not be negative");
+
+  auto &gc = vm.getGC();
+  auto cache = gc.createTable({});
+  auto order = gc.createList({});
+  return gc.createNativeFunction(functionName(fn), arity, memoCall,
+                                 {fn, cache, order, size, ttl});
+}
+
+static Value memoMemo(VM &vm, std::vector<Value> &args) {
+  return memoWrap(vm, args[0], 0, 0);
+}
+
+// Options: size (most entries kept, oldest evicted first) and ttl
+// (milliseconds an entry stays valid)
+static Value memoMemoWith(VM &vm, std::vector<Value> &args) {
+  auto options = expectOptions(vm, args[1])->hashMap;
+  std::int64_t size = 0;
+  if (options.count("size")) size = expectInt(vm, options["size"], "size");
+  std::int64_t ttl = 0;
+  if (options.count("ttl")) ttl = expectInt(vm, options["ttl"], "ttl");
+  return memoWrap(vm, args[0], size, ttl);
+}
+
+static void defineMemo(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("memo", gc.createNativeFunction("memo", 1, memoMemo));
+  vm.defineGlobal("memo_with",
+                  gc.createNativeFunction("memo_with", 2, memoMemoWith));
+}
+
+static bool isError(Value value) {
+  return std::holds_alternative<Object *>(value.value) &&
+         typeid(*std::get<Object *>(value.value)) == typeid(Error);
+}
+
+// Every decorator binds the wrapped function as its first data value and
+// logs to stderr, leaving stdout to the program
+
+static Value decoratorsTimedCall(VM &vm, std::vector<Value> &args) {
+  auto fn = vm.nativeData()[0];
+  auto start = std::chrono::steady_clock::now();
+  auto result = vm.call(fn, args);
+  std::chrono::duration<double, std::milli> elapsed =
+      std::chrono::steady_clock::now() - start;
+  std::cerr << "[timed] " << functionName(fn) << " took " << elapsed.count()
+            << "ms" << std::endl;
+  return result;
+}
+
+// Bound data: the wrapped function, the number of attempts and the delay
+// between them in milliseconds
+static Value decoratorsRetryCall(VM &vm, std::vector<Value> &args) {
+  auto fn = vm.nativeData()[0];
+  auto times = std::get<std::int64_t>(vm.nativeData()[1].value);
+  auto backoff = std::get<std::int64_t>(vm.nativeData()[2].value);
+
+  auto result = vm.call(fn, args);
+  for (std::int64_t attempt = 1; attempt < times && isError(result);
+       attempt++) {
+    std::this_thread::sleep_for(std::chrono::milliseconds(backoff));
+    result = vm.call(fn, args);
+  }
+  return result;
+}
+
+static Value decoratorsTraceCall(VM &vm, std::vector<Value> &args) {
+  auto fn = vm.nativeData()[0];
+  std::string argList;
+  for (std::size_t i = 0; i < args.size(); i++)
+    argList += (i == 0 ? "" : ", ") + args[i].toDbgString();
+
+  std::cerr << "[trace] " << functionName(fn) << "(" << argList << ")"
+            << std::endl;
+  auto result = vm.call(fn, args);
+  std::cerr << "[trace] " << functionName(fn) << " -> "
+            << result.toDbgString() << std::endl;
+  return result;
+}
+
+static Value decorate(VM &vm,
+                      Value fn,
+                      NativeFn call,
+                      std::vector<Value> extra = {}) {
+  std::uint16_t arity;
+  expectCallable(vm, fn, arity);
+  extra.insert(extra.begin(), fn);
+  return vm.getGC().createNativeFunction(functionName(fn), arity, call,
+                                         extra);
+}
+
+static Value decoratorsTimed(VM &vm, std::vector<Value> &args) {
+  return decorate(vm, args[0], decoratorsTimedCall);
+}
+
+// Options: times (attempts in total, default 3) and backoff (milliseconds to
+// wait before each retry, default 0); only error values trigger a retry
+static Value decoratorsRetry(VM &vm, std::vector<Value> &args) {
+  auto options = expectOptions(vm, args[1])->hashMap;
+  std::int64_t times = 3;
+  if (options.count("times")) times = expectInt(vm, options["times"], "times");
+  std::int64_t backoff = 0;
+  if (options.count("backoff"))
+    backoff = expectInt(vm, options["backoff"], "backoff");
+  if (times < 1 || backoff < 0)
+    vm.nativeError("Retry needs at least one attempt and a backoff of 0 or "
+                   "more");
+  return decorate(vm, args[0], decoratorsRetryCall, {times, backoff});
+}
+
+static Value decoratorsTrace(VM &vm, std::vector<Value> &args) {
+  return decorate(vm, args[0], decoratorsTraceCall);
+}
+
+static void defineDecorators(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap decorators;
+  decorators["timed"] = gc.createNativeFunction("timed", 1, decoratorsTimed);
+  decorators["retry"] = gc.createNativeFunction("retry", 2, decoratorsRetry);
+  decorators["trace"] = gc.createNativeFunction("trace", 1, decoratorsTrace);
+  vm.defineGlobal("decorators", gc.createTable(decorators));
+}
+
+static Value functionArity(VM &vm, std::vector<Value> &args) {
+  std::uint16_t arity;
+  expectCallable(vm, args[0], arity);
+  return static_cast<std::int64_t>(arity);
+}
+
+// Calls fn with the elements of a list as its arguments, so a wrapper can
+// forward whatever it was given without knowing the arity up front
+static Value functionApply(VM &vm, std::vector<Value> &args) {
+  std::uint16_t arity;
+  auto fn = expectCallable(vm, args[0], arity);
+  auto callArgs = expectList(vm, args[1])->elements;
+  if (callArgs.size() != arity) {
+    std::stringstream ss;
+    ss << functionName(fn) << " takes " << arity << " arguments but "
+       << callArgs.size() << " were given";
+    vm.nativeError(ss.str());
+  }
+  return vm.call(fn, callArgs);
+}
+
+static void defineFunctions(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("arity", gc.createNativeFunction("arity", 1, functionArity));
+  vm.defineGlobal("apply", gc.createNativeFunction("apply", 2, functionApply));
+}
+
+#ifndef FLAN_NO_EVENTS
+// An emitter's methods are natives bound to one shared table, which maps each
+// event name to a list of <listener, once> tuples. Methods are called
+// through CallMember, which passes no receiver, so the binding is what ties
+// them to their emitter.
+
+static std::string eventName(VM &vm, Value value) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Atom)) return static_cast<Atom *>(obj)->value;
+    if (typeid(*obj) == typeid(String))
+      return static_cast<String *>(obj)->value;
+  }
+  vm.nativeError("Expected an atom or string event but got " +
+                 value.toDbgString());
+}
+
+static List *listenersOf(VM &vm, std::string event) {
+  auto state =
+      static_cast<Table *>(std::get<Object *>(vm.nativeData()[0].value));
+  if (!state->hashMap.count(event)) {
+    auto list = vm.getGC().createList({});
+    state->hashMap[event] = list;
+  }
+  return static_cast<List *>(std::get<Object *>(state->hashMap[event].value));
+}
+
+static Value addListener(VM &vm, std::vector<Value> &args, bool once) {
+  auto event = eventName(vm, args[0]);
+  std::uint16_t arity;
+  expectCallable(vm, args[1], arity);
+
+  auto listeners = listenersOf(vm, event);
+  listeners->elements.push_back(vm.getGC().createTuple({args[1], once}));
+  return Value();
+}
+
+static Value eventsOn(VM &vm, std::vector<Value> &args) {
+  return addListener(vm, args, false);
+}
+
+static Value eventsOnce(VM &vm, std::vector<Value> &args) {
+  return addListener(vm, args, true);
+}
+
+// Listeners added or removed while an event is emitted take effect from the
+// next emit. Returns how many listeners were called.
+static Value eventsEmit(VM &vm, std::vector<Value> &args) {
+  auto listeners = listenersOf(vm, eventName(vm, args[0]));
+
+  auto snapshot = vm.getGC().createList(listeners->elements);
+  vm.root(snapshot);
+  auto &called =
+      static_cast<List *>(std::get<Object *>(snapshot.value))->elements;
+
+  auto &elements = listeners->elements;
+  elements.erase(std::remove_if(elements.begin(), elements.end(),
+                                [](Value &listener) {
+                                  auto tuple = static_cast<Tuple *>(
+                                      std::get<Object *>(listener.value));
+                                  return std::get<bool>(tuple->values[1].value);
+                                }),
+                 elements.end());
+
+  for (std::size_t i = 0; i < called.size(); i++) {
+    auto tuple = static_cast<Tuple *>(std::get<Object *>(called[i].value));
+    std::vector<Value> callArgs{args[1]};
+    vm.call(tuple->values[0], callArgs);
+  }
+  return static_cast<std::int64_t>(called.size());
+}
+
+// Removes every registration of the given listener; returns whether there
+// was any
+static Value eventsOff(VM &vm, std::vector<Value> &args) {
+  auto &elements = listenersOf(vm, eventName(vm, args[0]))->elements;
+  auto listener = args[1];
+  auto before = elements.size();
+  elements.erase(std::remove_if(elements.begin(), elements.end(),
+                                [&listener](Value &registered) {
+                                  auto tuple = static_cast<Tuple *>(
+                                      std::get<Object *>(registered.value));
+                                  return tuple->values[0].value ==
+                                         listener.value;
+                                }),
+                 elements.end());
+  return elements.size() != before;
+}
+
+static Value eventsEmitter(VM &vm, std::vector<Value> &) {
+  auto &gc = vm.getGC();
+  auto state = gc.createTable({});
+
+  OrderedMap emitter;
+  emitter["on"] = gc.createNativeFunction("on", 2, eventsOn, {state});
+  emitter["once"] = gc.createNativeFunction("once", 2, eventsOnce, {state});
+  emitter["emit"] = gc.createNativeFunction("emit", 2, eventsEmit, {state});
+  emitter["off"] = gc.createNativeFunction("off", 2, eventsOff, {state});
+  return gc.createTable(emitter);
+}
+
+static void defineEvents(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap events;
+  events["emitter"] = gc.createNativeFunction("emitter", 0, eventsEmitter);
+  vm.defineGlobal("events", gc.createTable(events));
+}
+
+// A machine is a table holding its spec and current state. The spec looks
+// like {initial: :idle, states: {idle: {on: {start: :running}, enter: fn,
+// exit: fn}, ...}}, where enter and exit are optional and called with the
+// event that caused the transition.
+
+static Table *tableField(VM &vm, Table *table, std::string key) {
+  if (table->hashMap.count(key))
+    return expectOptions(vm, table->hashMap[key]);
+  vm.nativeError("Missing '" + key + "' in state machine");
+}
+
+static Table *stateSpec(VM &vm, Table *spec, std::string state) {
+  auto states = tableField(vm, spec, "states");
+  if (!states->hashMap.count(state))
+    vm.nativeError("State machine has no state :" + state +
+                   didYouMean(state, states->hashMap));
+  return expectOptions(vm, states->hashMap[state]);
+}
+
+static Value fsmMachine(VM &vm, std::vector<Value> &args) {
+  auto spec = expectOptions(vm, args[0]);
+  if (!spec->hashMap.count("initial"))
+    vm.nativeError("Missing 'initial' in state machine");
+  auto initial = eventName(vm, spec->hashMap["initial"]);
+  stateSpec(vm, spec, initial);
+
+  OrderedMap machine;
+  machine["spec"] = args[0];
+  machine["state"] = vm.getGC().createAtom(initial);
+  return vm.getGC().createTable(machine);
+}
+
+static Value fsmState(VM &vm, std::vector<Value> &args) {
+  auto machine = expectOptions(vm, args[0]);
+  if (!machine->hashMap.count("state"))
+    vm.nativeError("Expected a state machine but got " +
+                   args[0].toDbgString());
+  return machine->hashMap["state"];
+}
+
+static void fireCallback(VM &vm, Table *state, std::string key, Value event) {
+  if (!state->hashMap.count(key)) return;
+  std::vector<Value> callArgs{event};
+  vm.call(state->hashMap[key], callArgs);
+}
+
+// Returns the new state, or _ when the current state has no transition for
+// the event, in which case nothing changes
+static Value fsmSend(VM &vm, std::vector<Value> &args) {
+  auto machine = expectOptions(vm, args[0]);
+  if (!machine->hashMap.count("spec") || !machine->hashMap.count("state"))
+    vm.nativeError("Expected a state machine but got " +
+                   args[0].toDbgString());
+  auto spec = expectOptions(vm, machine->hashMap["spec"]);
+  auto event = eventName(vm, args[1]);
+
+  auto from = stateSpec(vm, spec, eventName(vm, machine->hashMap["state"]));
+  if (!from->hashMap.count("on")) return Value();
+  auto transitions = expectOptions(vm, from->hashMap["on"]);
+  if (!transitions->hashMap.count(event)) return Value();
+
+  auto target = eventName(vm, transitions->hashMap[event]);
+  auto to = stateSpec(vm, spec, target);
+
+  fireCallback(vm, from, "exit", args[1]);
+  auto newState = vm.getGC().createAtom(target);
+  machine->hashMap["state"] = newState;
+  fireCallback(vm, to, "enter", args[1]);
+  return newState;
+}
+
+static void defineFSM(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap fsm;
+  fsm["machine"] = gc.createNativeFunction("machine", 1, fsmMachine);
+  fsm["state"] = gc.createNativeFunction("state", 1, fsmState);
+  fsm["send"] = gc.createNativeFunction("send", 2, fsmSend);
+  vm.defineGlobal("fsm", gc.createTable(fsm));
+}
+#endif
+
+#ifndef FLAN_NO_URL
+// Everything except RFC 3986 unreserved characters is percent-encoded
+static std::string urlEncode(const std::string &str) {
+  static const char *HEX = "0123456789ABCDEF";
+  std::string out;
+  for (unsigned char c : str) {
+    if (std::isalnum(c) || c == '-' || c == '.' || c == '_' || c == '~') {
+      out += static_cast<char>(c);
+    } else {
+      out += '%';
+      out += HEX[c >> 4];
+      out += HEX[c & 15];
+    }
+  }
+  return out;
+}
+
+// Returns false on a malformed escape. Query strings also use + for spaces.
+static bool urlDecode(const std::string &str, std::string &out, bool plus) {
+  out.clear();
+  for (std::size_t i = 0; i < str.size(); i++) {
+    if (str[i] == '%') {
+      if (i + 2 >= str.size() ||
+          !std::isxdigit(static_cast<unsigned char>(str[i + 1])) ||
+          !std::isxdigit(static_cast<unsigned char>(str[i + 2])))
+        return false;
+      out += static_cast<char>(std::stoi(str.substr(i + 1, 2), nullptr, 16));
+      i += 2;
+    } else if (plus && str[i] == '+') {
+      out += ' ';
+    } else {
+      out += str[i];
+    }
+  }
+  return true;
+}
+
+static Value urlEncodeNative(VM &vm, std::vector<Value> &args) {
+  return vm.getGC().createString(
+      urlEncode(expectString(vm, args[0], "to encode")));
+}
+
+static Value urlDecodeNative(VM &vm, std::vector<Value> &args) {
+  std::string out;
+  if (!urlDecode(expectString(vm, args[0], "to decode"), out, false))
+    return Value();
+  return vm.getGC().createString(out);
+}
+
+// Splits scheme://host:port/path?query#fragment into a table. Parts that are
+// absent are _, and the port is an integer. Returns _ for a malformed port.
+static Value urlParse(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to parse");
+  auto &gc = vm.getGC();
+  OrderedMap parts;
+  for (auto key : {"scheme", "host", "port", "path", "query", "fragment"})
+    parts[key] = Value();
+
+  auto hash = str.find('#');
+  if (hash != std::string::npos) {
+    parts["fragment"] = gc.createString(str.substr(hash + 1));
+    str.erase(hash);
+  }
+  auto question = str.find('?');
+  if (question != std::string::npos) {
+    parts["query"] = gc.createString(str.substr(question + 1));
+    str.erase(question);
+  }
+
+  // A scheme is a letter followed by letters, digits, + - or ., then a colon
+  auto colon = str.find(':');
+  if (colon != std::string::npos && colon > 0 &&
+      std::isalpha(static_cast<unsigned char>(str[0])) &&
+      std::all_of(str.begin(), str.begin() + colon, [](char c) {
+        return std::isalnum(static_cast<unsigned char>(c)) || c == '+' ||
+               c == '-' || c == '.';
+      })) {
+    parts["scheme"] = gc.createString(str.substr(0, colon));
+    str.erase(0, colon + 1);
+  }
+
+  if (str.compare(0, 2, "//") == 0) {
+    auto slash = str.find('/', 2);
+    auto authority = str.substr(2, slash == std::string::npos
+                                       ? std::string::npos
+                                       : slash - 2);
+    str = slash == std::string::npos ? "" : str.substr(slash);
+
+    // Credentials are not split out, and IPv6 hosts keep their brackets
+    auto at = authority.rfind('@');
+    if (at != std::string::npos) authority.erase(0, at + 1);
+    auto portColon = authority.rfind(':');
+    if (portColon != std::string::npos &&
+        authority.find(']', portColon) == std::string::npos) {
+      auto port = authority.substr(portColon + 1);
+      authority.erase(portColon);
+      if (!port.empty()) {
+        std::int64_t number;
+        auto [ptr, ec] =
+            std::from_chars(port.data(), port.data() + port.size(), number);
+        if (ec != std::errc() || ptr != port.data() + port.size() ||
+            number < 0 || number > 65535)
+          return Value();
+        parts["port"] = number;
+      }
+    }
+    parts["host"] = gc.createString(authority);
+  }
+
+  parts["path"] = gc.createString(str);
+  return gc.createTable(parts);
+}
+
+static Value urlBuild(VM &vm, std::vector<Value> &args) {
+  auto parts = expectOptions(vm, args[0])->hashMap;
+  auto part = [&](std::string key) -> std::string {
+    if (!parts.count(key) || std::holds_alternative<char>(parts[key].value))
+      return "";
+    return parts[key].toString();
+  };
+
+  std::string out;
+  if (!part("scheme").empty()) out += part("scheme") + ":";
+  if (!part("host").empty()) {
+    out += "//" + part("host");
+    if (!part("port").empty()) out += ":" + part("port");
+  }
+  out += part("path");
+  if (!part("query").empty()) out += "?" + part("query");
+  if (!part("fragment").empty()) out += "#" + part("fragment");
+  return vm.getGC().createString(out);
+}
+
+// Later duplicates win. Returns _ for a malformed escape.
+static Value urlQueryParse(VM &vm, std::vector<Value> &args) {
+  auto str = expectString(vm, args[0], "to parse");
+  OrderedMap table;
+
+  std::size_t start = 0;
+  while (start <= str.size()) {
+    auto end = str.find('&', start);
+    if (end == std::string::npos) end = str.size();
+    auto pair = str.substr(start, end - start);
+    start = end + 1;
+    if (pair.empty()) continue;
+
+    auto equals = pair.find('=');
+    std::string key, value;
+    if (!urlDecode(pair.substr(0, equals), key, true) ||
+        !urlDecode(equals == std::string::npos ? "" : pair.substr(equals + 1),
+                   value, true))
+      return Value();
+    table[key] = vm.getGC().createString(value);
+  }
+
+  return vm.getGC().createTable(table);
+}
+
+// Keys are written in sorted order so the same table always gives the same
+// string
+static Value urlQueryBuild(VM &vm, std::vector<Value> &args) {
+  auto &hashMap = expectOptions(vm, args[0])->hashMap;
+  std::vector<std::string> keys;
+  for (auto &pair : hashMap) keys.push_back(pair.first);
+  std::sort(keys.begin(), keys.end());
+
+  std::string out;
+  for (auto &key : keys) {
+    if (!out.empty()) out += "&";
+    out += urlEncode(key) + "=" + urlEncode(hashMap[key].toString());
+  }
+  return vm.getGC().createString(out);
+}
+
+static void defineURL(VM &vm) {
+  auto &gc = vm.getGC();
+  OrderedMap url;
+  url["parse"] = gc.createNativeFunction("parse", 1, urlParse);
+  url["build"] = gc.createNativeFunction("build", 1, urlBuild);
+  url["encode"] = gc.createNativeFunction("encode", 1, urlEncodeNative);
+  url["decode"] = gc.createNativeFunction("decode", 1, urlDecodeNative);
+  url["query_parse"] =
+      gc.createNativeFunction("query_parse", 1, urlQueryParse);
+  url["query_build"] =
+      gc.createNativeFunction("query_build", 1, urlQueryBuild);
+  vm.defineGlobal("url", gc.createTable(url));
+}
+#endif
+
+// A table can supply its own display string through a __string function,
+// which is called with the table itself
+static Value conversionString(VM &vm, std::vector<Value> &args) {
+  if (std::holds_alternative<Object *>(args[0].value)) {
+    auto obj = std::get<Object *>(args[0].value);
+    if (typeid(*obj) == typeid(Table) &&
+        static_cast<Table *>(obj)->hashMap.count("__string")) {
+      std::vector<Value> callArgs{args[0]};
+      auto result =
+          vm.call(static_cast<Table *>(obj)->hashMap["__string"], callArgs);
+      expectString(vm, result, "from __string");
+      return result;
+    }
+  }
+
+  return vm.getGC().createString(args[0].toString());
+}
+
+static Value conversionError(VM &vm, std::vector<Value> &args) {
+  return vm.getGC().createError(expectString(vm, args[0], "message"));
+}
+
+static void defineConversions(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("bool", gc.createNativeFunction("bool", 1, conversionBool));
+  vm.defineGlobal("string",
+                  gc.createNativeFunction("string", 1, conversionString));
+  vm.defineGlobal("error",
+                  gc.createNativeFunction("error", 1, conversionError));
+  vm.defineGlobal("int", gc.createNativeFunction("int", 2, numericInt));
+  vm.defineGlobal("float", gc.createNativeFunction("float", 1, numericFloat));
+  vm.defineGlobal("to_int",
+                  gc.createNativeFunction("to_int", 2, conversionToInt));
+  vm.defineGlobal("to_float",
+                  gc.createNativeFunction("to_float", 1, conversionToFloat));
+  vm.defineGlobal("format_int",
+                  gc.createNativeFunction("format_int", 2, numericFormatInt));
+  vm.defineGlobal(
+      "format_float",
+      gc.createNativeFunction("format_float", 2, numericFormatFloat));
+}
+
+static Value entriesOf(VM &vm, std::vector<const OrderedMap::Entry *> &pairs) {
+  auto &gc = vm.getGC();
+  std::vector<Value> entries;
+  entries.reserve(pairs.size());
+  for (auto pair : pairs)
+    entries.push_back(
+        gc.createTuple({gc.createString(pair->first), pair->second}));
+  return gc.createList(std::move(entries));
+}
+
+// Entries in the order their keys were first set
+static Value tableEntries(VM &vm, std::vector<Value> &args) {
+  auto table = expectTable(vm, args[0]);
+  std::vector<const OrderedMap::Entry *> pairs;
+  for (auto &pair : table->hashMap) pairs.push_back(&pair);
+  return entriesOf(vm, pairs);
+}
+
+static Value tableSortedEntries(VM &vm, std::vector<Value> &args) {
+  auto table = expectTable(vm, args[0]);
+  std::vector<const OrderedMap::Entry *> pairs;
+  for (auto &pair : table->hashMap) pairs.push_back(&pair);
+  std::sort(pairs.begin(), pairs.end(),
+            [](auto a, auto b) { return a->first < b->first; });
+  return entriesOf(vm, pairs);
+}
+
+static Error *expectError(VM &vm, Value value, const std::string &what) {
+  if (std::holds_alternative<Object *>(value.value)) {
+    auto obj = std::get<Object *>(value.value);
+    if (typeid(*obj) == typeid(Error)) return static_cast<Error *>(obj);
+  }
+  vm.nativeError("Expected an error " + what + " but got " +
+                 value.toDbgString());
+}
+
+static Value errorsWrap(VM &vm, std::vector<Value> &args) {
+  expectError(vm, args[0], "to wrap");
+  return vm.getGC().createError(expectString(vm, args[1], "context"),
+                                args[0]);
+}
+
+// The error followed by its causes, outermost first
+static Value errorsChain(VM &vm, std::vector<Value> &args) {
+  auto error = expectError(vm, args[0], "to follow");
+  std::vector<Value> chain{args[0]};
+  while (isError(error->cause)) {
+    chain.push_back(error->cause);
+    error = static_cast<Error *>(std::get<Object *>(error->cause.value));
+  }
+  return vm.getGC().createList(std::move(chain));
+}
+
+static Value errorsChainMethod(VM &vm, std::vector<Value> &) {
+  std::vector<Value> args{vm.nativeData()[0]};
+  return errorsChain(vm, args);
+}
+
+const std::vector<std::string> &flan::builtinMembers(Object *obj) {
+  static const std::vector<std::string> ERROR_MEMBERS = {"message", "cause",
+                                                         "chain"};
+  static const std::vector<std::string> FUNCTION_MEMBERS = {"name", "arity"};
+  static const std::vector<std::string> NONE;
+
+  if (typeid(*obj) == typeid(Error)) return ERROR_MEMBERS;
+  if (typeid(*obj) == typeid(Function) ||
+      typeid(*obj) == typeid(NativeFunction))
+    return FUNCTION_MEMBERS;
+  return NONE;
+}
+
+Value flan::builtinMember(VM &vm, Object *obj, const std::string &key) {
+  auto &gc = vm.getGC();
+  if (typeid(*obj) == typeid(Error)) {
+    auto error = static_cast<Error *>(obj);
+    if (key == "message") return gc.createString(error->message);
+    if (key == "cause") return error->cause;
+    return gc.createNativeFunction("chain", 0, errorsChainMethod, {error});
+  }
+
+  if (key == "name") return gc.createString(functionName(obj));
+  std::uint16_t arity;
+  expectCallable(vm, obj, arity);
+  return static_cast<std::int64_t>(arity);
+}
+
+static void defineErrors(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("wrap", gc.createNativeFunction("wrap", 2, errorsWrap));
+  vm.defineGlobal("chain", gc.createNativeFunction("chain", 1, errorsChain));
+}
+
+// A fallible function returns an error value when it fails and anything else
+// when it succeeds, the convention decorators.retry already relies on. There
+// is no wrapper around the success value, so mapping it and chaining another
+// fallible step are the same operation, and map_ok and and_then share an
+// implementation.
+
+static Value resultsIsOk(VM &, std::vector<Value> &args) {
+  return !isError(args[0]);
+}
+
+static Value resultsUnwrapOr(VM &, std::vector<Value> &args) {
+  return isError(args[0]) ? args[1] : args[0];
+}
+
+static Value resultsMapOk(VM &vm, std::vector<Value> &args) {
+  if (isError(args[0])) return args[0];
+  std::vector<Value> callArgs{args[0]};
+  return vm.call(args[1], callArgs);
+}
+
+static void defineResults(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("ok?", gc.createNativeFunction("ok?", 1, resultsIsOk));
+  vm.defineGlobal("unwrap_or",
+                  gc.createNativeFunction("unwrap_or", 2, resultsUnwrapOr));
+  vm.defineGlobal("map_ok",
+                  gc.createNativeFunction("map_ok", 2, resultsMapOk));
+  vm.defineGlobal("and_then",
+                  gc.createNativeFunction("and_then", 2, resultsMapOk));
+}
+
+static void defineEntries(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("entries",
+                  gc.createNativeFunction("entries", 1, tableEntries));
+  vm.defineGlobal(
+      "sorted_entries",
+      gc.createNativeFunction("sorted_entries", 1, tableSortedEntries));
+}
+
+#ifdef FLAN_PLUGINS
+// Bound data: a table of the modules loaded so far, keyed by path, so that
+// importing a library twice gives the same module. Libraries stay loaded for
+// the rest of the process, since the natives they define point into them.
+static Value pluginsImportNative(VM &vm, std::vector<Value> &args) {
+  auto path = expectString(vm, args[0], "path");
+  auto loaded =
+      static_cast<Table *>(std::get<Object *>(vm.nativeData()[0].value));
+  if (loaded->hashMap.count(path)) return loaded->hashMap[path];
+
+  auto handle = dlopen(path.c_str(), RTLD_NOW | RTLD_LOCAL);
+  // dlerror names the file itself
+  if (handle == nullptr)
+    vm.nativeError(std::string("Failed to load plugin: ") + dlerror());
+
+  // ISO C++ has no cast from an object pointer to a function pointer
+  auto symbol = dlsym(handle, PLUGIN_ENTRY);
+  PluginEntry entry = nullptr;
+  std::memcpy(&entry, &symbol, sizeof(entry));
+  auto plugin = entry == nullptr ? nullptr : entry();
+  if (plugin == nullptr) {
+    dlclose(handle);
+    vm.nativeError(path + " is not a Flan plugin");
+  }
+  if (plugin->abiVersion != PLUGIN_ABI_VERSION ||
+      plugin->valueSize != sizeof(Value)) {
+    auto abiVersion = plugin->abiVersion;
+    dlclose(handle);
+    vm.nativeError(path + " was built for plugin ABI " +
+                   std::to_string(abiVersion) + " but this build uses " +
+                   std::to_string(PLUGIN_ABI_VERSION));
+  }
+
+  OrderedMap module;
+  plugin->init(vm, module);
+  auto table = vm.getGC().createTable(module);
+  loaded->hashMap[path] = table;
+  return table;
+}
+
+static void definePlugins(VM &vm) {
+  auto &gc = vm.getGC();
+  vm.defineGlobal("import_native",
+                  gc.createNativeFunction("import_native", 1,
+                                          pluginsImportNative,
+                                          {gc.createTable({})}));
+}
+#endif
+
+// Module tables are only built when a program first reads them, so a short
+// script does not pay for every module at startup. Top-level natives are a
+// single allocation each and are defined right away.
+void flan::defineNatives(VM &vm) {
+  vm.defineLazyGlobal("io", defineIO);
+#ifndef FLAN_NO_OS
+  vm.defineLazyGlobal("os", defineOS);
+#endif
+  vm.defineLazyGlobal("runtime", defineRuntime);
+  vm.defineLazyGlobal("math", defineMath);
+#ifndef FLAN_NO_DECIMAL
+  vm.defineLazyGlobal("decimal", defineDecimal);
+#endif
+  vm.defineLazyGlobal("str", defineStr);
+  vm.defineLazyGlobal("binary", defineBinary);
+  defineConversions(vm);
+  defineTypes(vm);
+  defineComparisons(vm);
+  defineFolds(vm);
+  defineMemo(vm);
+  vm.defineLazyGlobal("decorators", defineDecorators);
+  defineFunctions(vm);
+#ifndef FLAN_NO_EVENTS
+  vm.defineLazyGlobal("events", defineEvents);
+  vm.defineLazyGlobal("fsm", defineFSM);
+#endif
+#ifndef FLAN_NO_URL
+  vm.defineLazyGlobal("url", defineURL);
+#endif
+  defineEntries(vm);
+  defineErrors(vm);
+  defineResults(vm);
+#ifdef FLAN_PLUGINS
+  definePlugins(vm);
+#endif
+}
@@ -0,0 +1,39 @@
+#include "optimizer.hpp"
+
+#include "vm.hpp"
+
+using namespace flan;
+
+static std::uint32_t readOffset(std::uint8_t *at) {
+  std::uint32_t value = 0;
+  for (auto i = 0; i < 4; i++)
+    value |= static_cast<std::uint32_t>(at[i]) << (i * 8);
+  return value;
+}
+
+static void writeOffset(std::uint8_t *at, std::uint32_t value) {
+  for (auto i = 0; i < 4; i++)
+    at[i] = static_cast<std::uint8_t>(value >> (i * 8));
+}
+
+std::size_t flan::threadJumps(std::uint8_t *buffer,
+                              const std::vector<std::size_t> &jumps) {
+  std::size_t rewritten = 0;
+
+  for (auto operand : jumps) {
+    auto next = operand + 4;
+    auto target = next + readOffset(buffer + operand);
+    auto threaded = target;
+    while (static_cast<InstructionType>(buffer[threaded]) ==
+           InstructionType::Jmp)
+      threaded = threaded + 5 + readOffset(buffer + threaded + 1);
+
+    if (threaded != target) {
+      writeOffset(buffer + operand,
+                  static_cast<std::uint32_t>(threaded - next));
+      rewritten++;
+    }
+  }
+
+  return rewritten;
+}
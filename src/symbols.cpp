@@ -0,0 +1,28 @@
+#include "symbols.hpp"
+
+using namespace flan;
+
+SymbolId SymbolTable::intern(const std::string &name) {
+  auto found = this->ids.find(name);
+  if (found != this->ids.end()) return found->second;
+
+  auto id = static_cast<SymbolId>(this->names.size());
+  this->names.push_back(name);
+  this->ids.insert({name, id});
+  return id;
+}
+
+bool SymbolTable::find(const std::string &name, SymbolId &id) const {
+  auto found = this->ids.find(name);
+  if (found == this->ids.end()) return false;
+  id = found->second;
+  return true;
+}
+
+const std::string &SymbolTable::name(SymbolId id) const {
+  return this->names[id];
+}
+
+std::size_t SymbolTable::size() const {
+  return this->names.size();
+}
@@ -0,0 +1,580 @@
+#include "verifier.hpp"
+
+#include <sstream>
+#include <string>
+#include <unordered_set>
+
+#include "extensions.hpp"
+
+using namespace flan;
+
+Verifier::Verifier(std::uint8_t *buffer, std::size_t size)
+    : buffer{buffer}, size{size}, limit{size} {}
+
+bool Verifier::verify() {
+  return this->verifyHeader() && this->verifyErrorInfoSection() &&
+         this->verifyNameSection() && this->verifyCode(this->size, 0, false);
+}
+
+bool Verifier::fail(std::string msg) {
+  std::stringstream ss;
+  ss << "Invalid bytecode at byte " << this->pos << ": " << msg;
+  this->error = ss.str();
+  return false;
+}
+
+bool Verifier::need(std::size_t count) {
+  if (this->limit - this->pos < count) return this->fail("Unexpected end");
+  return true;
+}
+
+std::uint8_t Verifier::readUInt8() {
+  return this->buffer[this->pos++];
+}
+
+std::uint16_t Verifier::readUInt16() {
+  auto low_byte = this->readUInt8();
+  auto high_byte = this->readUInt8();
+  return static_cast<std::uint16_t>(low_byte) |
+         (static_cast<std::uint16_t>(high_byte) << 8);
+}
+
+std::uint32_t Verifier::readUInt32() {
+  std::uint32_t value = 0;
+  for (auto i = 0; i < 4; i++)
+    value |= static_cast<std::uint32_t>(this->readUInt8()) << (i * 8);
+  return value;
+}
+
+bool Verifier::verifyHeader() {
+  if (!this->need(8)) return false;
+
+  for (auto i = 0; i < 4; i++)
+    if (this->readUInt8() != MAGIC_NUMBER[i])
+      return this->fail("Invalid Magic number");
+
+  std::uint8_t version[3];
+  for (auto &part : version) part = this->readUInt8();
+  auto error = incompatibility(version, this->readUInt8());
+  if (!error.empty()) {
+    // Not a malformed file, so the message is given as is
+    this->error = error;
+    return false;
+  }
+
+  return true;
+}
+
+bool Verifier::verifyErrorInfoSection() {
+  if (!this->need(2)) return false;
+  this->errorInfoCount = this->readUInt16();
+
+  for (auto i = 0; i < this->errorInfoCount; i++) {
+    if (!this->need(4)) return false;
+    this->readUInt16();
+    auto length = this->readUInt16();
+    if (!this->need(length)) return false;
+    this->pos += length;
+  }
+
+  return true;
+}
+
+bool Verifier::verifyNameSection() {
+  if (!this->need(2)) return false;
+  this->nameCount = this->readUInt16();
+  for (auto i = 0; i < this->nameCount; i++)
+    if (!this->verifyShortString()) return false;
+  return true;
+}
+
+bool Verifier::verifyCode(std::size_t end,
+                          std::int64_t depth,
+                          bool isFunction) {
+  auto prevLimit = this->limit;
+  this->limit = end;
+
+  // Jumps only go forward, so by the time an instruction is reached every
+  // jump that can land on it has already been seen. Targets are kept in
+  // order so that a bad one is always reported at the lowest offset.
+  std::map<std::size_t, std::int64_t> targets;
+  std::unordered_set<std::size_t> boundaries;
+  bool reachable = true;
+  // Upvalues the function literal loaded by the previous instruction needs,
+  // or -1 when that instruction was not such a load
+  std::int64_t closable = -1;
+
+  while (this->pos < end) {
+    auto start = this->pos;
+    boundaries.insert(start);
+
+    // A depth of -1 marks a target only reached from unreachable code.
+    if (targets.count(start) && targets[start] >= 0) {
+      if (reachable && targets[start] != depth)
+        return this->fail("Inconsistent stack depth at jump target");
+      depth = targets[start];
+      reachable = true;
+    }
+
+    auto instType = static_cast<InstructionType>(this->readUInt8());
+    auto needed = closable;
+    closable = -1;
+    if (needed > 0 && instType != InstructionType::Closure)
+      return this->fail("Function uses upvalues but is not closed over");
+
+    std::int64_t pops = 0;
+    std::int64_t pushes = 0;
+    bool isJump = false;
+    // Values a taken jump leaves on the stack on top of the fallthrough depth
+    std::int64_t kept = 0;
+    bool terminates = false;
+
+    switch (instType) {
+      case InstructionType::LoadNeg1:
+      case InstructionType::Load0:
+      case InstructionType::Load1:
+      case InstructionType::Load2:
+      case InstructionType::Load3:
+      case InstructionType::Load4:
+      case InstructionType::Load5:
+        pushes = 1;
+        break;
+
+      case InstructionType::Load:
+        if (!this->verifyValue()) return false;
+        if (this->loadedFunction) closable = this->loadedUpvalues;
+        pushes = 1;
+        break;
+
+      case InstructionType::Push: {
+        if (!this->need(1)) return false;
+        auto length = this->readUInt8();
+        for (auto i = 0; i < length; i++) {
+          if (!this->verifyValue()) return false;
+          if (this->loadedFunction && this->loadedUpvalues > 0)
+            return this->fail("Function uses upvalues but is not closed over");
+        }
+        pushes = length;
+        break;
+      }
+
+      case InstructionType::Pop:
+        pops = 1;
+        break;
+
+      case InstructionType::PopN:
+        if (!this->need(1)) return false;
+        pops = this->readUInt8();
+        break;
+
+      case InstructionType::Nip:
+        pops = 2;
+        pushes = 1;
+        break;
+
+      case InstructionType::NipN:
+        if (!this->need(1)) return false;
+        pops = this->readUInt8() + 1;
+        pushes = 1;
+        break;
+
+      case InstructionType::Dup:
+        pops = 1;
+        pushes = 2;
+        break;
+
+      case InstructionType::Dup2:
+        pops = 2;
+        pushes = 4;
+        break;
+
+      case InstructionType::Add:
+      case InstructionType::Sub:
+      case InstructionType::Mul:
+      case InstructionType::Div:
+      case InstructionType::Mod:
+      case InstructionType::Eq:
+      case InstructionType::NEq:
+      case InstructionType::LT:
+      case InstructionType::LTE:
+      case InstructionType::GT:
+      case InstructionType::GTE:
+        if (!this->verifyErrInfoIdx()) return false;
+        pops = 2;
+        pushes = 1;
+        break;
+
+      case InstructionType::And:
+      case InstructionType::Or:
+        pops = 2;
+        pushes = 1;
+        break;
+
+      case InstructionType::Not:
+      case InstructionType::Negate:
+        pops = 1;
+        pushes = 1;
+        break;
+
+      case InstructionType::Jmp:
+        isJump = true;
+        terminates = true;
+        break;
+
+      case InstructionType::Jz:
+      case InstructionType::Jnz:
+        isJump = true;
+        pops = 1;
+        break;
+
+      case InstructionType::JzOrPop:
+      case InstructionType::JnzOrPop:
+        isJump = true;
+        pops = 1;
+        kept = 1;
+        break;
+
+      case InstructionType::InitList:
+      case InstructionType::InitTup:
+        if (!this->need(4)) return false;
+        pops = this->readUInt32();
+        pushes = 1;
+        break;
+
+      case InstructionType::InitTable: {
+        if (!this->need(4)) return false;
+        auto length = this->readUInt32();
+        for (std::uint32_t i = 0; i < length; i++)
+          if (!this->verifyShortString()) return false;
+        pops = length;
+        pushes = 1;
+        break;
+      }
+
+      case InstructionType::IdxListOrTup:
+        if (!this->verifyErrInfoIdx() || !this->need(4)) return false;
+        this->pos += 4;
+        pops = 1;
+        pushes = 1;
+        break;
+
+      case InstructionType::SetList:
+        if (!this->verifyErrInfoIdx() || !this->need(4)) return false;
+        this->pos += 4;
+        pops = 2;
+        break;
+
+      case InstructionType::DestructureList:
+        if (!this->verifyErrInfoIdx() || !this->need(4)) return false;
+        pops = 1;
+        pushes = this->readUInt32();
+        break;
+
+      case InstructionType::DestructureObject: {
+        if (!this->verifyErrInfoIdx() || !this->need(4)) return false;
+        auto count = this->readUInt32();
+        for (std::uint32_t i = 0; i < count; i++)
+          if (!this->verifyShortString()) return false;
+        pops = 1;
+        pushes = count;
+        break;
+      }
+
+      case InstructionType::GetMember:
+        if (!this->verifyErrInfoIdx() || !this->verifyShortString())
+          return false;
+        pops = 1;
+        pushes = 1;
+        break;
+
+      case InstructionType::SetMember:
+        if (!this->verifyErrInfoIdx() || !this->verifyShortString())
+          return false;
+        pops = 2;
+        break;
+
+      case InstructionType::DefGlobal:
+      case InstructionType::OverrideGlobal:
+      case InstructionType::SetGlobal:
+        if (!this->verifyErrInfoIdx() || !this->verifyNameIdx()) return false;
+        pops = 1;
+        break;
+
+      case InstructionType::GetGlobal:
+        if (!this->verifyErrInfoIdx() || !this->verifyNameIdx()) return false;
+        pushes = 1;
+        break;
+
+      case InstructionType::GetLocal:
+      case InstructionType::SetLocal: {
+        if (!this->need(2)) return false;
+        auto idx = this->readUInt16();
+        if (reachable && idx >= depth)
+          return this->fail("Local slot out of range");
+        if (instType == InstructionType::GetLocal) {
+          pushes = 1;
+        } else {
+          pops = 1;
+          pushes = 1;
+        }
+        break;
+      }
+
+      case InstructionType::IncLocal:
+      case InstructionType::DecLocal: {
+        if (!this->verifyErrInfoIdx() || !this->need(2)) return false;
+        auto idx = this->readUInt16();
+        if (reachable && idx >= depth)
+          return this->fail("Local slot out of range");
+        pushes = 1;
+        break;
+      }
+
+      case InstructionType::IncGlobal:
+      case InstructionType::DecGlobal:
+        if (!this->verifyErrInfoIdx() || !this->verifyNameIdx()) return false;
+        pushes = 1;
+        break;
+
+      case InstructionType::CallFn:
+        if (!this->verifyErrInfoIdx() || !this->need(2)) return false;
+        pops = this->readUInt16() + 1;
+        pushes = 1;
+        break;
+
+      case InstructionType::CallMember:
+        if (!this->verifyErrInfoIdx() || !this->verifyShortString() ||
+            !this->need(2))
+          return false;
+        pops = this->readUInt16() + 1;
+        pushes = 1;
+        break;
+
+      case InstructionType::GetIndex:
+        if (!this->verifyErrInfoIdx()) return false;
+        pops = 2;
+        pushes = 1;
+        break;
+
+      case InstructionType::SetIndex:
+        if (!this->verifyErrInfoIdx()) return false;
+        pops = 3;
+        break;
+
+      // The function must be the one loaded right before, which no jump may
+      // skip
+      case InstructionType::Closure: {
+        if (needed < 0 || targets.count(start))
+          return this->fail("Closure does not follow a function literal");
+        if (!this->need(2)) return false;
+        auto count = this->readUInt16();
+        if (count < needed)
+          return this->fail("Closure gives fewer upvalues than are used");
+        for (auto i = 0; i < count; i++) {
+          if (!this->need(3)) return false;
+          auto isLocal = this->readUInt8();
+          auto idx = this->readUInt16();
+          if (isLocal > 1) return this->fail("Invalid upvalue source");
+          if (isLocal) {
+            if (reachable && idx >= depth)
+              return this->fail("Local slot out of range");
+          } else if (!this->useUpvalue(idx, isFunction)) {
+            return false;
+          }
+        }
+        pops = 1;
+        pushes = 1;
+        break;
+      }
+
+      case InstructionType::GetUpvalue:
+      case InstructionType::SetUpvalue:
+        if (!this->need(2) || !this->useUpvalue(this->readUInt16(), isFunction))
+          return false;
+        if (instType == InstructionType::GetUpvalue) {
+          pushes = 1;
+        } else {
+          pops = 1;
+          pushes = 1;
+        }
+        break;
+
+      case InstructionType::CloseUpvalue:
+        pops = 1;
+        break;
+
+      case InstructionType::Ext: {
+        if (!this->need(1)) return false;
+        auto extension = findExtension(this->readUInt8());
+        if (extension == nullptr)
+          return this->fail("Extension instruction not available");
+        if (!this->verifyErrInfoIdx()) return false;
+        pops = extension->operands;
+        pushes = 1;
+        break;
+      }
+
+      case InstructionType::RetFn:
+        if (!isFunction) return this->fail("Return outside of a function");
+        pops = 1;
+        terminates = true;
+        break;
+
+      case InstructionType::Halt:
+        if (isFunction) return this->fail("Halt inside a function");
+        terminates = true;
+        break;
+
+      default: {
+        std::stringstream ss;
+        ss << "Invalid instruction " << static_cast<int>(instType);
+        return this->fail(ss.str());
+      }
+    }
+
+    if (reachable) {
+      if (depth < pops) return this->fail("Stack underflow");
+      depth += pushes - pops;
+    }
+
+    if (isJump &&
+        !this->verifyJump(end, reachable ? depth + kept : -1, targets))
+      return false;
+
+    if (terminates) reachable = false;
+  }
+
+  if (reachable)
+    return this->fail(isFunction ? "Function body does not return"
+                                 : "Program does not halt");
+
+  for (auto &target : targets)
+    if (!boundaries.count(target.first)) {
+      this->pos = target.first;
+      return this->fail("Jump into the middle of an instruction");
+    }
+
+  this->limit = prevLimit;
+  return true;
+}
+
+bool Verifier::verifyValue() {
+  this->loadedFunction = false;
+  if (!this->need(1)) return false;
+  auto type = this->readUInt8();
+
+  switch (type) {
+    case 0:
+      if (!this->need(4)) return false;
+      this->pos += 4;
+      return true;
+    case 1:
+      if (!this->need(8)) return false;
+      this->pos += 8;
+      return true;
+    case 2:
+      if (!this->need(1)) return false;
+      this->pos += 1;
+      return true;
+    case 3:
+      return true;
+    case 4: {
+      if (!this->need(2)) return false;
+      auto length = this->readUInt16();
+      if (!this->need(length)) return false;
+      this->pos += length;
+      return true;
+    }
+    case 5:
+      return this->verifyShortString();
+    case 6: {
+      if (!this->verifyShortString() || !this->need(6)) return false;
+      auto arity = this->readUInt16();
+      auto length = this->readUInt32();
+      if (!this->need(static_cast<std::size_t>(length) + 1)) return false;
+
+      if (this->nesting == MAX_FUNCTION_NESTING)
+        return this->fail("Function literals are nested too deeply");
+      this->nesting++;
+      auto outerUpvalues = this->upvaluesUsed;
+      this->upvaluesUsed = 0;
+      if (!this->verifyCode(this->pos + length, arity + 1, true)) return false;
+      this->loadedFunction = true;
+      this->loadedUpvalues = this->upvaluesUsed;
+      this->upvaluesUsed = outerUpvalues;
+      this->nesting--;
+
+      if (static_cast<InstructionType>(this->readUInt8()) !=
+          InstructionType::EndFn)
+        return this->fail("Function body is not terminated");
+      return true;
+    }
+    case 7:
+      if (!this->need(8)) return false;
+      this->pos += 8;
+      return true;
+    case 8: {
+      if (!this->need(4)) return false;
+      auto length = this->readUInt32();
+      if (!this->need(length)) return false;
+      this->pos += length;
+      return true;
+    }
+    default: {
+      std::stringstream ss;
+      ss << "Invalid value type " << static_cast<int>(type);
+      return this->fail(ss.str());
+    }
+  }
+}
+
+bool Verifier::verifyShortString() {
+  if (!this->need(1)) return false;
+  auto length = this->readUInt8();
+  if (!this->need(length)) return false;
+  this->pos += length;
+  return true;
+}
+
+bool Verifier::useUpvalue(std::uint16_t idx, bool isFunction) {
+  if (!isFunction) return this->fail("Upvalue outside of a function");
+  if (idx >= this->upvaluesUsed) this->upvaluesUsed = idx + 1u;
+  return true;
+}
+
+bool Verifier::verifyErrInfoIdx() {
+  if (!this->need(2)) return false;
+  if (this->readUInt16() >= this->errorInfoCount)
+    return this->fail("Error info index out of range");
+  return true;
+}
+
+bool Verifier::verifyNameIdx() {
+  if (!this->need(2)) return false;
+  if (this->readUInt16() >= this->nameCount)
+    return this->fail("Name index out of range");
+  return true;
+}
+
+bool Verifier::verifyJump(
+    std::size_t end,
+    std::int64_t depth,
+    std::map<std::size_t, std::int64_t> &targets) {
+  if (!this->need(4)) return false;
+  this->jumps.push_back(this->pos);
+  auto offset = this->readUInt32();
+  auto target = this->pos + offset;
+
+  if (target >= end) return this->fail("Jump target out of range");
+
+  if (depth < 0) {
+    targets.insert({target, depth});
+  } else {
+    if (targets.count(target) && targets[target] >= 0 &&
+        targets[target] != depth)
+      return this->fail("Inconsistent stack depth at jump target");
+    targets[target] = depth;
+  }
+
+  return true;
+}
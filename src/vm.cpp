@@ -1,5 +1,6 @@
 #include "vm.hpp"
 
+#include <algorithm>
 #include <cmath>
 #include <cstdint>
 #include <cstdlib>
@@ -8,143 +9,278 @@
 #include <iomanip>
 #include <ios>
 #include <iostream>
+#include <iterator>
 #include <sstream>
 #include <string>
+#include <utility>
 #include <variant>
 
+#include "disassembler.hpp"
+#include "extensions.hpp"
 #include "gc.hpp"
+#include "natives.hpp"
+#include "optimizer.hpp"
+#include "verifier.hpp"
 
 using namespace flan;
 
-VM::VM(fs::path fileName) : stack{}, gc{GC(this->stack.actualStack())} {
+static std::size_t levenshtein(const std::string& a, const std::string& b) {
+  std::vector<std::size_t> row(b.size() + 1);
+  for (std::size_t j = 0; j <= b.size(); j++) row[j] = j;
+
+  for (std::size_t i = 1; i <= a.size(); i++) {
+    auto diagonal = row[0];
+    row[0] = i;
+    for (std::size_t j = 1; j <= b.size(); j++) {
+      auto above = row[j];
+      auto cost = a[i - 1] == b[j - 1] ? 0 : 1;
+      row[j] = std::min({row[j] + 1, row[j - 1] + 1, diagonal + cost});
+      diagonal = above;
+    }
+  }
+
+  return row[b.size()];
+}
+
+std::string flan::didYouMean(const std::string& name,
+                             const std::vector<std::string>& known) {
+  auto maxDistance = std::max<std::size_t>(1, name.size() / 3);
+  std::string best;
+  std::size_t bestDistance = maxDistance + 1;
+
+  for (auto& candidate : known) {
+    auto distance = levenshtein(name, candidate);
+    if (distance < bestDistance ||
+        (distance == bestDistance && !best.empty() && candidate < best)) {
+      best = candidate;
+      bestDistance = distance;
+    }
+  }
+
+  if (best.empty()) return "";
+  return ", did you mean '" + best + "'?";
+}
+
+static std::string versionString(const std::uint8_t version[3]) {
+  std::stringstream ss;
+  ss << static_cast<int>(version[0]) << '.' << static_cast<int>(version[1])
+     << '.' << static_cast<int>(version[2]);
+  return ss.str();
+}
+
+std::string flan::incompatibility(const std::uint8_t version[3],
+                                  std::uint8_t features) {
+  auto newer = std::lexicographical_compare(VERSION, VERSION + 2, version,
+                                            version + 2);
+  if (version[0] != VERSION[0] || version[1] != VERSION[1]) {
+    std::stringstream ss;
+    ss << "Bytecode was compiled for Flan " << versionString(version)
+       << " but this is Flan " << versionString(VERSION) << ", "
+       << (newer ? "update the Flan runtime" : "recompile it");
+    return ss.str();
+  }
+
+  auto missing = features & ~SUPPORTED_FEATURES;
+  if (missing != 0) {
+    std::stringstream ss;
+    ss << "Bytecode needs features this runtime was built without:";
+    if (missing & FEATURE_LIST_MATH) ss << " list-math";
+    if (missing & ~FEATURE_LIST_MATH) ss << " unknown";
+    return ss.str();
+  }
+
+  return "";
+}
+
+VM::VM(fs::path fileName, VMOptions options)
+    : stack{},
+      gc{GC({this->stack.actualStack(), &this->globals, &this->constants,
+              &this->openUpvalues})},
+      options{options} {
   this->callframes.reserve(CALL_FRAMES_MAX);
+  this->gc.setLog(options.gcLog);
+  this->gc.pause();
+  defineNatives(*this);
+  this->gc.resume();
 
-  auto inputStream = std::ifstream(fileName);
+  auto inputStream = std::ifstream(fileName, std::ios::binary);
   this->fileName = fileName;
 
   if (!inputStream.is_open()) {
     std::stringstream ss;
-    ss << "Failed to open file" << this->fileName;
+    ss << "Failed to open file " << this->fileName;
     this->throwError(ss.str());
   }
 
-  std::streamsize size = inputStream.tellg();
-  inputStream.seekg(0, std::ios::beg);
+  this->load(inputStream);
+  inputStream.close();
+}
+
+VM::VM(std::istream& inputStream, fs::path fileName, VMOptions options)
+    : stack{},
+      gc{GC({this->stack.actualStack(), &this->globals, &this->constants,
+              &this->openUpvalues})},
+      options{options} {
+  this->callframes.reserve(CALL_FRAMES_MAX);
+  this->gc.setLog(options.gcLog);
+  this->gc.pause();
+  defineNatives(*this);
+  this->gc.resume();
+  this->fileName = fileName;
+  this->load(inputStream);
+}
 
-  this->buffer = new char[size];
+void VM::load(std::istream& inputStream) {
+  std::string contents{std::istreambuf_iterator<char>(inputStream),
+                       std::istreambuf_iterator<char>()};
 
-  if (!inputStream.read(buffer, size)) {
+  if (inputStream.bad()) {
     std::stringstream ss;
     ss << "Failed to read file " << this->fileName;
     this->throwError(ss.str());
   }
 
-  inputStream.close();
+  // Skip a `#!` line so that compiled files can be run as scripts
+  std::size_t offset = 0;
+  if (contents.rfind("#!", 0) == 0) {
+    auto newline = contents.find('\n');
+    offset = newline == std::string::npos ? contents.size() : newline + 1;
+  }
 
-  this->readErrorInfoSection();
+  this->bufferSize = contents.size() - offset;
+  this->buffer = new char[this->bufferSize];
+  std::memcpy(this->buffer, contents.data() + offset, this->bufferSize);
+
+  auto verifier = Verifier(reinterpret_cast<std::uint8_t*>(this->buffer),
+                           this->bufferSize);
+  if (!verifier.verify()) this->throwError(verifier.error);
+  threadJumps(reinterpret_cast<std::uint8_t*>(this->buffer), verifier.jumps);
 }
 
 VM::~VM() {
   delete[] this->buffer;
 }
 
-void VM::readErrorInfoSection() {
-  auto bufferPtr = reinterpret_cast<std::uint8_t*>(this->buffer);
-  auto errorInfoListLength = this->readUInt16(bufferPtr);
+void VM::readErrorInfoSection(Cursor& cursor) {
+  auto errorInfoListLength = this->readUInt16(cursor);
   this->errorInfoList.reserve(errorInfoListLength);
 
   for (auto i = 0; i < errorInfoListLength; i++) {
     ErrorInfo errInfo;
-    errInfo.line = this->readUInt16(bufferPtr);
+    errInfo.line = this->readUInt16(cursor);
 
-    auto length = this->readUInt16(bufferPtr);
+    auto length = this->readUInt16(cursor);
     std::string lineText;
     lineText.reserve(length);
     for (auto i = 0; i < length; i++)
-      lineText += static_cast<char>(this->readUInt8(bufferPtr));
+      lineText += static_cast<char>(this->readUInt8(cursor));
     errInfo.lineText = lineText;
 
     this->errorInfoList.push_back(errInfo);
   }
 }
 
-void VM::run() {
-  auto bufferPtr = reinterpret_cast<std::uint8_t*>(this->buffer);
+void VM::readNameSection(Cursor& cursor) {
+  auto count = this->readUInt16(cursor);
+  this->names.reserve(count);
+  for (auto i = 0; i < count; i++)
+    this->names.push_back(this->globalSlot(this->readShortString(cursor)));
+}
+
+SymbolId VM::readName(Cursor& cursor) {
+  auto idx = this->readUInt16(cursor);
+  if (idx >= this->names.size()) this->throwError("Name index out of range");
+  return this->names[idx];
+}
+
+void VM::disassemble(std::ostream& out) {
+  Disassembler(reinterpret_cast<std::uint8_t*>(this->buffer), this->bufferSize,
+               out)
+      .disassemble();
+}
+
+Value VM::run() {
+  auto cursor =
+      Cursor(reinterpret_cast<std::uint8_t*>(this->buffer), this->bufferSize);
 
-  if (!this->checkMagicNumber(bufferPtr)) {
+  if (!this->checkMagicNumber(cursor)) {
     this->throwError("Invalid Magic number");
   }
-  if (!this->checkVersion(bufferPtr)) {
-    this->throwError("Update the Flan runtime");
-  }
+  this->checkVersion(cursor);
 
+  this->readErrorInfoSection(cursor);
+  this->readNameSection(cursor);
+
+  // No RetFn can bring the call stack down to SIZE_MAX, so only Halt ends the
+  // top-level run
+  return this->execute(cursor, SIZE_MAX);
+}
+
+// Runs instructions until Halt, or until a RetFn pops the call stack back
+// down to `returnDepth` frames, which is how VM::call waits for its callee
+Value VM::execute(Cursor& cursor, std::size_t returnDepth) {
   for (;;) {
-    auto instType = static_cast<InstructionType>(*bufferPtr);
+    if (this->options.maxInstructions != 0 &&
+        ++this->instructionCount > this->options.maxInstructions) {
+      std::stringstream ss;
+      ss << "Instruction budget of " << this->options.maxInstructions
+         << " exhausted";
+      this->throwRuntimeError(ss.str());
+    }
+    auto instType = static_cast<InstructionType>(this->readUInt8(cursor));
 
     switch (instType) {
       case InstructionType::LoadNeg1:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(-1)));
         break;
 
       case InstructionType::Load0:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(0)));
         break;
 
       case InstructionType::Load1:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(1)));
         break;
 
       case InstructionType::Load2:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(2)));
         break;
 
       case InstructionType::Load3:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(3)));
         break;
 
       case InstructionType::Load4:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(4)));
         break;
 
       case InstructionType::Load5:
-        bufferPtr++;
         this->push(Value(static_cast<std::int64_t>(5)));
         break;
 
       case InstructionType::Load:
-        bufferPtr++;
-        this->push(this->readValue(bufferPtr));
+        this->push(this->readValue(cursor));
         break;
 
       case InstructionType::Push: {
-        bufferPtr++;
-        auto length = this->readUInt8(bufferPtr);
+        auto length = this->readUInt8(cursor);
         for (auto i = 0; i < length; i++)
-          this->push(this->readValue(bufferPtr));
+          this->push(this->readValue(cursor));
         break;
       }
 
       case InstructionType::Pop: {
-        bufferPtr++;
         this->pop();
         break;
       }
 
       case InstructionType::PopN: {
-        bufferPtr++;
-        auto length = this->readUInt8(bufferPtr);
+        auto length = this->readUInt8(cursor);
         for (auto i = 0; i < length; i++) this->pop();
         break;
       }
 
       case InstructionType::Nip: {
-        bufferPtr++;
         auto last = this->pop();
         this->pop();
         this->push(last);
@@ -152,8 +288,7 @@ void VM::run() {
       }
 
       case InstructionType::NipN: {
-        bufferPtr++;
-        auto length = this->readUInt8(bufferPtr);
+        auto length = this->readUInt8(cursor);
         auto last = this->pop();
         for (auto i = 0; i < length; i++) this->pop();
         this->push(last);
@@ -161,137 +296,149 @@ void VM::run() {
       }
 
       case InstructionType::Dup: {
-        bufferPtr++;
         auto value = this->stack.last();
         this->push(value);
         break;
       }
 
+      // a b -> a b a b, so `xs[i] += 1` evaluates xs and i only once
+      case InstructionType::Dup2: {
+        auto second = this->stack.fromLast(2);
+        auto first = this->stack.fromLast(1);
+        this->push(second);
+        this->push(first);
+        break;
+      }
+
       case InstructionType::Add:
-        bufferPtr++;
-        this->push(this->performAdd(this->readUInt16(bufferPtr)));
+        this->push(this->performAdd(this->readUInt16(cursor)));
         break;
 
       case InstructionType::Sub:
-        bufferPtr++;
-        this->push(this->performSub(this->readUInt16(bufferPtr)));
+        this->push(this->performSub(this->readUInt16(cursor)));
         break;
 
       case InstructionType::Mul:
-        bufferPtr++;
-        this->push(this->performMul(this->readUInt16(bufferPtr)));
+        this->push(this->performMul(this->readUInt16(cursor)));
         break;
 
       case InstructionType::Div:
-        bufferPtr++;
-        this->push(this->performDiv(this->readUInt16(bufferPtr)));
+        this->push(this->performDiv(this->readUInt16(cursor)));
         break;
 
       case InstructionType::Mod:
-        bufferPtr++;
-        this->push(this->performMod(this->readUInt16(bufferPtr)));
+        this->push(this->performMod(this->readUInt16(cursor)));
         break;
 
       case InstructionType::Eq:
-        bufferPtr++;
-        this->push(this->performEq(this->readUInt16(bufferPtr)));
+        this->push(this->performEq(this->readUInt16(cursor)));
         break;
 
       case InstructionType::NEq:
-        bufferPtr++;
-        this->push(this->performNEq(this->readUInt16(bufferPtr)));
+        this->push(this->performNEq(this->readUInt16(cursor)));
         break;
 
       case InstructionType::LT:
-        bufferPtr++;
-        this->push(this->performLT(this->readUInt16(bufferPtr)));
+        this->push(this->performLT(this->readUInt16(cursor)));
         break;
 
       case InstructionType::LTE:
-        bufferPtr++;
-        this->push(this->performLTE(this->readUInt16(bufferPtr)));
+        this->push(this->performLTE(this->readUInt16(cursor)));
         break;
 
       case InstructionType::GT:
-        bufferPtr++;
-        this->push(this->performLTE(this->readUInt16(bufferPtr)));
+        this->push(this->performGT(this->readUInt16(cursor)));
         break;
 
       case InstructionType::GTE:
-        bufferPtr++;
-        this->push(this->performGTE(this->readUInt16(bufferPtr)));
+        this->push(this->performGTE(this->readUInt16(cursor)));
         break;
 
       case InstructionType::And:
-        bufferPtr++;
         this->push(this->performAnd());
         break;
 
       case InstructionType::Or:
-        bufferPtr++;
         this->push(this->performOr());
         break;
 
       case InstructionType::Not: {
-        bufferPtr++;
         Value& last = this->stack.last();
         last.value = !last.truthy();
         break;
       }
 
       case InstructionType::Negate: {
-        bufferPtr++;
         auto value = this->pop();
         if (std::holds_alternative<std::int64_t>(value.value)) {
           auto integer = std::get<std::int64_t>(value.value);
+          if (integer == INT64_MIN)
+            this->throwRuntimeError("Integer overflow in negation");
           this->push(-integer);
         } else if (std::holds_alternative<double>(value.value)) {
           auto floatNum = std::get<double>(value.value);
           this->push(-floatNum);
+        } else {
+          std::stringstream ss;
+          ss << "Cannot negate " << value.toDbgString();
+          this->throwRuntimeError(ss.str());
         }
         break;
       }
 
       case InstructionType::Jmp: {
-        bufferPtr++;
-        this->jumpForward(bufferPtr, this->readUInt32(bufferPtr));
+        this->jumpForward(cursor, this->readUInt32(cursor));
         break;
       }
 
       case InstructionType::Jz: {
-        bufferPtr++;
-        auto offset = this->readUInt32(bufferPtr);
-        if (!this->pop().truthy()) this->jumpForward(bufferPtr, offset);
+        auto offset = this->readUInt32(cursor);
+        if (!this->pop().truthy()) this->jumpForward(cursor, offset);
         break;
       }
 
       case InstructionType::Jnz: {
-        bufferPtr++;
-        auto offset = this->readUInt32(bufferPtr);
-        if (this->pop().truthy()) this->jumpForward(bufferPtr, offset);
+        auto offset = this->readUInt32(cursor);
+        if (this->pop().truthy()) this->jumpForward(cursor, offset);
+        break;
+      }
+
+      case InstructionType::JzOrPop: {
+        auto offset = this->readUInt32(cursor);
+        if (!this->stack.last().truthy())
+          this->jumpForward(cursor, offset);
+        else
+          this->pop();
         break;
       }
 
+      case InstructionType::JnzOrPop: {
+        auto offset = this->readUInt32(cursor);
+        if (this->stack.last().truthy())
+          this->jumpForward(cursor, offset);
+        else
+          this->pop();
+        break;
+      }
+
+      // Literal elements are pushed in source order, so they are taken off
+      // the stack as a block rather than popped one by one in reverse
       case InstructionType::InitList: {
-        bufferPtr++;
-        auto length = this->readUInt32(bufferPtr);
-        std::vector<Value> elements;
-        elements.reserve(length);
-        for (std::uint32_t i = 0; i < length; i++)
-          elements.push_back(this->pop());
+        auto length = this->readUInt32(cursor);
+        auto elements = this->popN(length);
         this->push(this->gc.createList(std::move(elements)));
         break;
       }
 
       case InstructionType::InitTable: {
-        bufferPtr++;
-        auto length = this->readUInt32(bufferPtr);
-        std::unordered_map<std::string, Value> hashMap;
+        auto length = this->readUInt32(cursor);
+        auto values = this->popN(length);
+        OrderedMap hashMap;
         hashMap.reserve(length);
 
         for (std::uint32_t i = 0; i < length; i++) {
-          auto key = this->readShortString(bufferPtr);
-          hashMap[key] = this->pop();
+          auto key = this->readShortString(cursor);
+          hashMap[key] = values[i];
         }
 
         this->push(this->gc.createTable(hashMap));
@@ -300,120 +447,98 @@ void VM::run() {
       }
 
       case InstructionType::InitTup: {
-        bufferPtr++;
-        auto length = this->readUInt32(bufferPtr);
-        std::vector<Value> values;
-        values.reserve(length);
-        for (std::uint32_t i = 0; i < length; i++)
-          values.push_back(this->pop());
+        auto length = this->readUInt32(cursor);
+        auto values = this->popN(length);
         this->push(this->gc.createTuple(std::move(values)));
         break;
       }
 
       case InstructionType::IdxListOrTup: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto idx = std::get<std::int64_t>(this->readInteger(bufferPtr).value);
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto idx = this->readInteger(cursor);
         auto value = this->pop();
 
-        if (!std::holds_alternative<Object*>(value.value)) {
-          std::stringstream ss;
-          ss << "Expected a list or tuple but got " << value.toDbgString();
-          this->throwError(errInfoIdx, ss.str());
+        if (std::holds_alternative<Object*>(value.value)) {
+          auto obj = std::get<Object*>(value.value);
+          if ((typeid(*obj) == typeid(List)) ||
+              (typeid(*obj) == typeid(Tuple))) {
+            this->push(this->getIndex(value, idx, errInfoIdx));
+            break;
+          }
         }
 
-        auto obj = std::get<Object*>(value.value);
-
-        std::vector<Value> values;
-        if (typeid(obj) == typeid(List)) {
-          values = static_cast<List*>(obj)->elements;
-        } else if (typeid(obj) == typeid(Tuple)) {
-          values = static_cast<Tuple*>(obj)->values;
-        } else {
-          std::stringstream ss;
-          ss << "Expected a list or tuple but got " << value.toDbgString();
-          this->throwError(errInfoIdx, ss.str());
-        }
-
-        if (values.size() <= static_cast<std::uint64_t>(idx))
-          this->throwError(errInfoIdx, "Index out of range");
-
-        if ((idx < 0) && (0 <= static_cast<std::int64_t>(values.size() - idx)))
-          this->push(values.at(values.size() - idx));
-        else
-          this->push(values.at(idx));
-
-        break;
+        std::stringstream ss;
+        ss << "Expected a list or tuple but got " << value.toDbgString();
+        this->throwError(errInfoIdx, ss.str());
       }
 
       case InstructionType::SetList: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto idx = std::get<std::int64_t>(this->readInteger(bufferPtr).value);
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto idx = this->readInteger(cursor);
         auto newValue = this->pop();
         auto couldBeList = this->pop();
 
-        if (!std::holds_alternative<Object*>(couldBeList.value)) {
-          std::stringstream ss;
-          ss << "Expected a list but got " << couldBeList.toDbgString();
-          this->throwError(errInfoIdx, ss.str());
+        if (std::holds_alternative<Object*>(couldBeList.value)) {
+          auto obj = std::get<Object*>(couldBeList.value);
+          if (typeid(*obj) == typeid(List)) {
+            this->setIndex(couldBeList, idx, newValue, errInfoIdx);
+            break;
+          }
         }
 
-        auto obj = std::get<Object*>(couldBeList.value);
-        if (typeid(obj) != typeid(List)) {
-          std::stringstream ss;
-          ss << "Expected a list but got " << couldBeList.toDbgString();
-          this->throwError(errInfoIdx, ss.str());
-        }
-
-        auto elements = static_cast<List*>(obj)->elements;
-        if (elements.size() <= static_cast<std::uint64_t>(idx))
-          this->throwError(errInfoIdx, "Index out of range");
-
-        if ((idx < 0) &&
-            (0 <= static_cast<std::int64_t>(elements.size() - idx)))
-          elements[elements.size() - idx] = newValue;
-        else
-          elements[idx] = newValue;
+        std::stringstream ss;
+        ss << "Expected a list but got " << couldBeList.toDbgString();
+        this->throwError(errInfoIdx, ss.str());
+      }
 
+      case InstructionType::GetIndex: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto key = this->pop();
+        auto value = this->pop();
+        this->push(this->getIndex(value, key, errInfoIdx));
         break;
       }
 
-      case InstructionType::GetMember: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto key = this->readShortString(bufferPtr);
+      case InstructionType::SetIndex: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto newValue = this->pop();
+        auto key = this->pop();
         auto value = this->pop();
+        this->setIndex(value, key, newValue, errInfoIdx);
+        break;
+      }
 
-        if (!std::holds_alternative<Object*>(value.value)) {
-          std::stringstream ss;
-          ss << "Expected a table but got " << value.toDbgString();
-          this->throwError(errInfoIdx, ss.str());
-        }
-
-        auto obj = std::get<Object*>(value.value);
-        if (typeid(obj) != typeid(Table)) {
-          std::stringstream ss;
-          ss << "Expected a table but got " << value.toDbgString();
-          this->throwError(errInfoIdx, ss.str());
-        }
+      case InstructionType::DestructureList: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto count = this->readUInt32(cursor);
+        this->destructureList(this->pop(), count, errInfoIdx);
+        break;
+      }
 
-        auto table = static_cast<Table*>(obj);
-        if (!table->hashMap.count(key)) {
-          std::stringstream ss;
-          ss << "Table does not have key " << value.toDbgString();
-          this->throwError(ss.str());
+      // Pushes the value of each key in turn, and a missing key is an error
+      // just as it is for GetMember
+      case InstructionType::DestructureObject: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto count = this->readUInt32(cursor);
+        auto value = this->pop();
+        for (std::uint32_t i = 0; i < count; i++) {
+          auto key = this->readShortString(cursor);
+          this->push(this->getMember(value, key, errInfoIdx));
         }
+        break;
+      }
 
-        this->push(table->hashMap[key]);
-
+      case InstructionType::GetMember: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto key = this->readShortString(cursor);
+        auto value = this->pop();
+        this->push(this->getMember(value, key, errInfoIdx));
         break;
       }
 
       case InstructionType::SetMember: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto key = this->readShortString(bufferPtr);
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto key = this->readShortString(cursor);
         auto newValue = this->pop();
         auto couldBeTable = this->pop();
 
@@ -424,7 +549,7 @@ void VM::run() {
         }
 
         auto obj = std::get<Object*>(couldBeTable.value);
-        if (typeid(obj) != typeid(Table)) {
+        if (typeid(*obj) != typeid(Table)) {
           std::stringstream ss;
           ss << "Expected a table but got " << couldBeTable.toDbgString();
           this->throwError(errInfoIdx, ss.str());
@@ -436,113 +561,411 @@ void VM::run() {
         break;
       }
 
-      case InstructionType::DefGlobal: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto varName = this->readShortString(bufferPtr);
+      case InstructionType::DefGlobal:
+      case InstructionType::OverrideGlobal: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto id = this->readName(cursor);
         auto value = this->pop();
-
-        if (this->globals.count(varName)) {
-          std::stringstream ss;
-          ss << "Global variable '" << varName << "' is already defined";
-          this->throwError(errInfoIdx, ss.str());
-        } else {
-          this->globals.insert({varName, value});
-        }
-
+        this->defineUserGlobal(id, value,
+                               instType == InstructionType::OverrideGlobal,
+                               errInfoIdx);
         break;
       }
 
       case InstructionType::GetGlobal: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto varName = this->readShortString(bufferPtr);
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto id = this->readName(cursor);
 
-        if (!this->globals.count(varName)) {
-          std::stringstream ss;
-          ss << "Global variable '" << varName << "' is not defined";
-          this->throwError(errInfoIdx, ss.str());
-        } else {
-          this->push(this->globals[varName]);
-        }
+        if (this->globalKinds[id] == GlobalKind::Undefined)
+          this->undefinedGlobal(errInfoIdx, id);
+        if (this->globalKinds[id] == GlobalKind::Lazy) this->initLazy(id);
+        this->push(this->globals[id]);
 
         break;
       }
 
       case InstructionType::SetGlobal: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto varName = this->readShortString(bufferPtr);
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto id = this->readName(cursor);
         auto value = this->pop();
 
-        if (!this->globals.count(varName)) {
-          std::stringstream ss;
-          ss << "Global variable '" << varName << "' is not defined";
-          this->throwError(errInfoIdx, ss.str());
-        } else {
-          this->globals.insert_or_assign(varName, value);
-        }
+        this->checkAssignable(id, errInfoIdx);
+        this->globals[id] = value;
 
         break;
       }
 
       case InstructionType::GetLocal: {
-        bufferPtr++;
-        auto idx = this->readUInt16(bufferPtr);
+        auto idx = this->readUInt16(cursor);
         push(this->stack[idx]);
         break;
       }
 
       case InstructionType::SetLocal: {
-        bufferPtr++;
-        auto idx = this->readUInt16(bufferPtr);
+        auto idx = this->readUInt16(cursor);
         this->stack[idx] = this->stack.last();
         break;
       }
 
+      // The increments leave the new value on the stack, which is what ++x
+      // evaluates to. x++ evaluates to the old value, so the compiler reads
+      // the variable first and pops what the increment leaves.
+      case InstructionType::IncLocal:
+      case InstructionType::DecLocal: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto idx = this->readUInt16(cursor);
+        auto delta = instType == InstructionType::IncLocal ? 1 : -1;
+        auto& local = this->stack[idx];
+        local = this->increment(local, delta, errInfoIdx);
+        this->push(local);
+        break;
+      }
+
+      case InstructionType::IncGlobal:
+      case InstructionType::DecGlobal: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto id = this->readName(cursor);
+        auto delta = instType == InstructionType::IncGlobal ? 1 : -1;
+
+        this->checkAssignable(id, errInfoIdx);
+        auto& global = this->globals[id];
+        global = this->increment(global, delta, errInfoIdx);
+        this->push(global);
+        break;
+      }
+
       case InstructionType::CallFn: {
-        bufferPtr++;
-        auto errInfoIdx = this->readUInt16(bufferPtr);
-        auto argCount = this->readUInt16(bufferPtr);
-        auto couldBeFunc = this->stack.fromLast(argCount - 1);
-        this->callFunc(bufferPtr, couldBeFunc, argCount, errInfoIdx);
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto argCount = this->readUInt16(cursor);
+        auto couldBeFunc = this->stack.fromLast(argCount + 1);
+        this->callFunc(cursor, couldBeFunc, argCount, errInfoIdx);
         break;
       }
 
       case InstructionType::RetFn: {
-        bufferPtr++;
-
+        auto result = this->pop();
         auto poppedFrame = this->callframes.back();
         this->callframes.pop_back();
 
-        bufferPtr = poppedFrame.retAddr;
+        // Drop the callee, its arguments and its locals
+        this->closeUpvalues(this->stack.from);
+        this->stack.stack.resize(this->stack.from);
+        cursor = poppedFrame.retAddr;
         this->stack.from = poppedFrame.prevFrom;
+        this->push(result);
 
+        if (this->callframes.size() == returnDepth) return result;
         break;
       }
 
-      case InstructionType::Halt:
-        bufferPtr++;
-        goto quitRun;
+      case InstructionType::CallMember: {
+        auto errInfoIdx = this->readUInt16(cursor);
+        auto key = this->readShortString(cursor);
+        auto argCount = this->readUInt16(cursor);
+
+        // The member replaces the table below the arguments, which is
+        // exactly where CallFn expects the callee to be
+        auto& receiver = this->stack.fromLast(argCount + 1);
+        receiver = this->getMember(receiver, key, errInfoIdx);
+        this->callFunc(cursor, receiver, argCount, errInfoIdx);
         break;
+      }
+
+      // Fills in the upvalues of the function literal just loaded. Each one
+      // is a u8 that is 1 to capture a local slot of the running function or
+      // 0 to share one of its upvalues, followed by the u16 slot or index.
+      case InstructionType::Closure: {
+        auto count = this->readUInt16(cursor);
+        auto func =
+            static_cast<Function*>(std::get<Object*>(this->stack.last().value));
+        func->upvalues.reserve(count);
+        for (auto i = 0; i < count; i++) {
+          auto isLocal = this->readUInt8(cursor);
+          auto index = this->readUInt16(cursor);
+          func->upvalues.push_back(
+              isLocal ? this->captureUpvalue(this->stack.from + index)
+                      : this->callframes.back().function->upvalues[index]);
+        }
+        break;
+      }
+
+      case InstructionType::GetUpvalue: {
+        auto idx = this->readUInt16(cursor);
+        auto upvalue = this->callframes.back().function->upvalues[idx];
+        this->push(this->upvalueRef(upvalue));
+        break;
+      }
+
+      case InstructionType::SetUpvalue: {
+        auto idx = this->readUInt16(cursor);
+        auto upvalue = this->callframes.back().function->upvalues[idx];
+        this->upvalueRef(upvalue) = this->stack.last();
+        break;
+      }
+
+      // Pops a local that goes out of scope before its function returns
+      case InstructionType::CloseUpvalue:
+        this->closeUpvalues(this->stack.stack.size() - 1);
+        this->pop();
+        break;
+
+      case InstructionType::Ext: {
+        auto id = this->readUInt8(cursor);
+        auto errInfoIdx = this->readUInt16(cursor);
+        this->callExtension(id, errInfoIdx);
+        break;
+      }
+
+      case InstructionType::Halt:
+        if (this->stack.stack.empty()) return Value();
+        return this->stack.last();
 
       default: {
         std::stringstream ss;
         ss << "Invalid instruction " << std::hex << std::setw(2)
-           << std::setfill('0') << static_cast<std::uint8_t>(instType);
+           << std::setfill('0') << static_cast<int>(instType);
         this->throwError(ss.str());
         break;
       }
     }
+  }
+}
+
+// An override must shadow a builtin, and a plain definition must not unless
+// the VM was started with allowOverride
+void VM::defineUserGlobal(SymbolId id,
+                          Value value,
+                          bool override,
+                          std::uint16_t errInfoIdx) {
+  auto& name = this->symbols.name(id);
+  std::stringstream ss;
+  if (this->globalKinds[id] == GlobalKind::User) {
+    ss << "Global variable '" << name << "' is already defined";
+    this->throwError(errInfoIdx, ss.str());
+  }
 
-    bufferPtr++;
+  auto isBuiltin = this->globalKinds[id] == GlobalKind::Builtin ||
+                   this->globalKinds[id] == GlobalKind::Lazy;
+  if (override && !isBuiltin) {
+    ss << "Global variable '" << name << "' does not override a builtin";
+    this->throwError(errInfoIdx, ss.str());
+  }
+  if (!override && isBuiltin && !this->options.allowOverride) {
+    ss << "Global variable '" << name
+       << "' would shadow a builtin, use override to redefine it";
+    this->throwError(errInfoIdx, ss.str());
   }
 
-quitRun:
-  return;
+  this->globals[id] = value;
+  this->globalKinds[id] = GlobalKind::User;
+}
+
+void VM::checkAssignable(SymbolId id, std::uint16_t errInfoIdx) {
+  if (this->globalKinds[id] == GlobalKind::Undefined)
+    this->undefinedGlobal(errInfoIdx, id);
+  if (this->globalKinds[id] != GlobalKind::User) {
+    std::stringstream ss;
+    ss << "Cannot assign to builtin '" << this->symbols.name(id)
+       << "', define a global that overrides it instead";
+    this->throwError(errInfoIdx, ss.str());
+  }
+}
+
+// Builds a module the first time a program reads it. The initializer
+// defines the global itself, which turns the slot into a plain builtin.
+void VM::initLazy(SymbolId id) {
+  auto init = this->lazyInits.at(id);
+  this->lazyInits.erase(id);
+  this->gc.pause();
+  init(*this);
+  this->gc.resume();
+}
+
+void VM::undefinedGlobal(std::uint16_t errInfoIdx, SymbolId undefined) {
+  auto& name = this->symbols.name(undefined);
+  std::vector<std::string> known;
+  for (SymbolId id = 0; id < this->symbols.size(); id++)
+    if (this->globalKinds[id] != GlobalKind::Undefined)
+      known.push_back(this->symbols.name(id));
+
+  std::stringstream ss;
+  ss << "Global variable '" << name << "' is not defined"
+     << didYouMean(name, known);
+  this->throwError(errInfoIdx, ss.str());
+}
+
+Value VM::getMember(Value value, std::string key, std::uint16_t errInfoIdx) {
+  if (!std::holds_alternative<Object*>(value.value)) {
+    std::stringstream ss;
+    ss << "Expected a table but got " << value.toDbgString();
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  auto obj = std::get<Object*>(value.value);
+  if (typeid(*obj) != typeid(Table)) {
+    auto& members = builtinMembers(obj);
+    if (std::find(members.begin(), members.end(), key) != members.end())
+      return builtinMember(*this, obj, key);
+
+    std::stringstream ss;
+    if (members.empty())
+      ss << "Expected a table but got " << value.toDbgString();
+    else
+      ss << value.toDbgString() << " does not have member '" << key << "'"
+         << didYouMean(key, members);
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  auto table = static_cast<Table*>(obj);
+  if (!table->hashMap.count(key)) {
+    std::stringstream ss;
+    ss << "Table does not have key '" << key << "'"
+       << didYouMean(key, table->hashMap);
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  return table->hashMap[key];
+}
+
+std::size_t VM::resolveIndex(Value key,
+                             std::size_t size,
+                             std::uint16_t errInfoIdx) {
+  if (!std::holds_alternative<std::int64_t>(key.value)) {
+    std::stringstream ss;
+    ss << "Expected an integer index but got " << key.toDbgString();
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  // Negative indices count from the end
+  auto idx = std::get<std::int64_t>(key.value);
+  auto resolved = idx < 0 ? idx + static_cast<std::int64_t>(size) : idx;
+  if ((resolved < 0) || (static_cast<std::size_t>(resolved) >= size)) {
+    std::stringstream ss;
+    ss << "Index " << idx << " out of range for length " << size;
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  return static_cast<std::size_t>(resolved);
+}
+
+// Tables are keyed by strings, and atoms stand in for their text
+static bool tableKey(Value key, std::string& out) {
+  if (!std::holds_alternative<Object*>(key.value)) return false;
+
+  auto obj = std::get<Object*>(key.value);
+  if (typeid(*obj) == typeid(String)) {
+    out = static_cast<String*>(obj)->value;
+    return true;
+  } else if (typeid(*obj) == typeid(Atom)) {
+    out = static_cast<Atom*>(obj)->value;
+    return true;
+  }
+  return false;
+}
+
+Value VM::getIndex(Value value, Value key, std::uint16_t errInfoIdx) {
+  if (std::holds_alternative<Object*>(value.value)) {
+    auto obj = std::get<Object*>(value.value);
+
+    if (typeid(*obj) == typeid(List)) {
+      auto& elements = static_cast<List*>(obj)->elements;
+      return elements[this->resolveIndex(key, elements.size(), errInfoIdx)];
+    } else if (typeid(*obj) == typeid(Tuple)) {
+      auto& values = static_cast<Tuple*>(obj)->values;
+      return values[this->resolveIndex(key, values.size(), errInfoIdx)];
+    } else if (typeid(*obj) == typeid(Table)) {
+      std::string k;
+      if (!tableKey(key, k)) {
+        std::stringstream ss;
+        ss << "Expected a string or atom key but got " << key.toDbgString();
+        this->throwError(errInfoIdx, ss.str());
+      }
+      return this->getMember(value, k, errInfoIdx);
+    }
+  }
+
+  std::stringstream ss;
+  ss << "Cannot index " << value.toDbgString();
+  this->throwError(errInfoIdx, ss.str());
+}
+
+Value VM::increment(Value value, std::int64_t delta, std::uint16_t errInfoIdx) {
+  if (std::holds_alternative<std::int64_t>(value.value)) {
+    std::int64_t result;
+    if (__builtin_add_overflow(std::get<std::int64_t>(value.value), delta,
+                               &result))
+      this->throwError(errInfoIdx, delta > 0 ? "Integer overflow in ++"
+                                             : "Integer overflow in --");
+    return result;
+  } else if (std::holds_alternative<double>(value.value)) {
+    return std::get<double>(value.value) + static_cast<double>(delta);
+  }
+
+  std::stringstream ss;
+  ss << "Cannot " << (delta > 0 ? "increment " : "decrement ")
+     << value.toDbgString();
+  this->throwError(errInfoIdx, ss.str());
+}
+
+// Pushes the first `count` elements of a list or tuple. Extra elements are
+// ignored, so `[a, b] = xs` works on any xs with at least two elements.
+void VM::destructureList(Value value,
+                         std::uint32_t count,
+                         std::uint16_t errInfoIdx) {
+  std::vector<Value>* elements = nullptr;
+  if (std::holds_alternative<Object*>(value.value)) {
+    auto obj = std::get<Object*>(value.value);
+    if (typeid(*obj) == typeid(List))
+      elements = &static_cast<List*>(obj)->elements;
+    else if (typeid(*obj) == typeid(Tuple))
+      elements = &static_cast<Tuple*>(obj)->values;
+  }
+
+  if (elements == nullptr) {
+    std::stringstream ss;
+    ss << "Cannot destructure " << value.toDbgString() << " as a list";
+    this->throwError(errInfoIdx, ss.str());
+  }
+  if (elements->size() < count) {
+    std::stringstream ss;
+    ss << "Cannot destructure " << elements->size() << " elements into "
+       << count << " names";
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  for (std::uint32_t i = 0; i < count; i++) this->push((*elements)[i]);
+}
+
+void VM::setIndex(Value value,
+                  Value key,
+                  Value newValue,
+                  std::uint16_t errInfoIdx) {
+  if (std::holds_alternative<Object*>(value.value)) {
+    auto obj = std::get<Object*>(value.value);
+
+    if (typeid(*obj) == typeid(List)) {
+      auto& elements = static_cast<List*>(obj)->elements;
+      elements[this->resolveIndex(key, elements.size(), errInfoIdx)] =
+          newValue;
+      return;
+    } else if (typeid(*obj) == typeid(Table)) {
+      std::string k;
+      if (!tableKey(key, k)) {
+        std::stringstream ss;
+        ss << "Expected a string or atom key but got " << key.toDbgString();
+        this->throwError(errInfoIdx, ss.str());
+      }
+      static_cast<Table*>(obj)->hashMap[k] = newValue;
+      return;
+    }
+  }
+
+  std::stringstream ss;
+  ss << "Cannot assign to an index of " << value.toDbgString();
+  this->throwError(errInfoIdx, ss.str());
 }
 
-void VM::callFunc(std::uint8_t* bufferPtr,
+void VM::callFunc(Cursor& cursor,
                   Value couldBeFunc,
                   std::uint16_t argCount,
                   std::uint16_t errInfoIdx) {
@@ -553,7 +976,12 @@ void VM::callFunc(std::uint8_t* bufferPtr,
   }
 
   auto obj = std::get<Object*>(couldBeFunc.value);
-  if (typeid(obj) != typeid(Function)) {
+  if (typeid(*obj) == typeid(NativeFunction)) {
+    this->callNative(static_cast<NativeFunction*>(obj), argCount, errInfoIdx);
+    return;
+  }
+
+  if (typeid(*obj) != typeid(Function)) {
     std::stringstream ss;
     ss << couldBeFunc.toDbgString() << " is not callable";
     this->throwError(errInfoIdx, ss.str());
@@ -568,201 +996,274 @@ void VM::callFunc(std::uint8_t* bufferPtr,
     this->throwError(errInfoIdx, ss.str());
   }
 
-  auto frame = CallFrame(bufferPtr, func, this->stack.from);
+  if (this->callframes.size() >= this->options.maxCallDepth) {
+    std::stringstream ss;
+    ss << "Maximum recursion depth exceeded (" << this->options.maxCallDepth
+       << " frames)";
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  auto frame = CallFrame(cursor, func, this->stack.from);
   this->callframes.push_back(frame);
   this->stack.setFrom(argCount);
-  bufferPtr = func->buffers;
+  cursor = Cursor(func->chunk);
+}
+
+void VM::callNative(NativeFunction* native,
+                    std::uint16_t argCount,
+                    std::uint16_t errInfoIdx) {
+  if (native->arity != argCount) {
+    std::stringstream ss;
+    ss << "<native function " << native->name << "> takes " << native->arity
+       << " arguments but " << argCount << " was given";
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  auto& args = this->borrowArgs();
+  for (auto i = argCount; i > 0; i--) args.push_back(this->stack.fromLast(i));
+
+  // Arguments stay on the stack while the native runs so the GC can see them,
+  // and anything it roots is pushed above them
+  auto base = this->stack.stack.size() - argCount - 1;
+  auto prevNative = this->currentNative;
+  this->nativeErrInfoIdx = errInfoIdx;
+  this->currentNative = native;
+  this->gc.pause();
+  auto result = native->fn(*this, args);
+  this->gc.resume();
+  this->currentNative = prevNative;
+  this->argsDepth--;
+
+  this->stack.stack.resize(base);
+  this->push(result);
+}
+
+// Native calls reuse one argument vector per nesting level, so a call only
+// allocates the first time it reaches a new depth or a larger argument count
+std::vector<Value>& VM::borrowArgs() {
+  if (this->argsDepth == this->argBuffers.size())
+    this->argBuffers.emplace_back();
+  auto& args = this->argBuffers[this->argsDepth++];
+  args.clear();
+  return args;
+}
+
+// Extensions run like natives, with their operands kept on the stack
+void VM::callExtension(std::uint8_t id, std::uint16_t errInfoIdx) {
+  auto extension = findExtension(id);
+  if (extension == nullptr) {
+    std::stringstream ss;
+    ss << "Extension instruction " << static_cast<int>(id)
+       << " is not available in this build";
+    this->throwError(errInfoIdx, ss.str());
+  }
+
+  auto& operands = this->borrowArgs();
+  for (auto i = extension->operands; i > 0; i--)
+    operands.push_back(this->stack.fromLast(i));
+
+  auto base = this->stack.stack.size() - extension->operands;
+  this->nativeErrInfoIdx = errInfoIdx;
+  this->gc.pause();
+  auto result = extension->fn(*this, operands);
+  this->gc.resume();
+  this->argsDepth--;
+
+  this->stack.stack.resize(base);
+  this->push(result);
+}
+
+// Operands of + - * / % after coercion. Two integers stay integers, and an
+// integer paired with a float is widened to a float. Nothing else coerces, so
+// bools, _ and objects are left to the caller to reject.
+struct Operands {
+  enum { None, Int, Float } kind = None;
+  std::int64_t intLeft = 0, intRight = 0;
+  double floatLeft = 0.0, floatRight = 0.0;
+};
+
+static Operands coerceOperands(Value& left, Value& right) {
+  Operands ops;
+  auto leftInt = std::holds_alternative<std::int64_t>(left.value);
+  auto rightInt = std::holds_alternative<std::int64_t>(right.value);
+  auto leftFloat = std::holds_alternative<double>(left.value);
+  auto rightFloat = std::holds_alternative<double>(right.value);
+
+  if (leftInt && rightInt) {
+    ops.kind = Operands::Int;
+    ops.intLeft = std::get<std::int64_t>(left.value);
+    ops.intRight = std::get<std::int64_t>(right.value);
+  } else if ((leftInt || leftFloat) && (rightInt || rightFloat)) {
+    ops.kind = Operands::Float;
+    ops.floatLeft =
+        leftInt ? static_cast<double>(std::get<std::int64_t>(left.value))
+                : std::get<double>(left.value);
+    ops.floatRight =
+        rightInt ? static_cast<double>(std::get<std::int64_t>(right.value))
+                 : std::get<double>(right.value);
+  }
+
+  return ops;
+}
+
+static String* asString(Value& value) {
+  if (!std::holds_alternative<Object*>(value.value)) return nullptr;
+  auto obj = std::get<Object*>(value.value);
+  if (typeid(*obj) != typeid(String)) return nullptr;
+  return static_cast<String*>(obj);
 }
 
 Value VM::performAdd(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<Object*>(left.value)) {
-    auto leftObj = std::get<Object*>(left.value);
-    if (typeid(leftObj) == typeid(String)) {
-      auto l = static_cast<String*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(String)) {
-          auto r = static_cast<String*>(rightObj);
-          return this->gc.createString(l->value + r->value);
-        }
-      }
-    }
-  } else if (std::holds_alternative<std::int64_t>(left.value)) {
-    auto l = std::get<std::int64_t>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      return l + r;
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      return static_cast<double>(l) + r;
-    }
-  } else if (std::holds_alternative<double>(left.value)) {
-    auto l = std::get<double>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      return l + static_cast<double>(r);
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      return l + r;
-    }
+  auto ops = coerceOperands(left, right);
+  if (ops.kind == Operands::Int) {
+    std::int64_t result;
+    if (__builtin_add_overflow(ops.intLeft, ops.intRight, &result))
+      this->throwError(errInfoIdx, "Integer overflow in addition");
+    return result;
+  } else if (ops.kind == Operands::Float) {
+    return ops.floatLeft + ops.floatRight;
   }
 
+  auto l = asString(left);
+  auto r = asString(right);
+  if (l && r) return this->gc.createString(l->value + r->value);
+
   std::stringstream ss;
   ss << "Cannot add " << left.toDbgString() << " and " << right.toDbgString();
   this->throwError(errInfoIdx, ss.str());
-
-  // Won't run
-  return left;
 }
 
 Value VM::performSub(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<std::int64_t>(left.value)) {
-    auto l = std::get<std::int64_t>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      return l - r;
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      return static_cast<double>(l) - r;
-    }
-  } else if (std::holds_alternative<double>(left.value)) {
-    auto l = std::get<double>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      return l - static_cast<double>(r);
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      return l - r;
-    }
+  auto ops = coerceOperands(left, right);
+  if (ops.kind == Operands::Int) {
+    std::int64_t result;
+    if (__builtin_sub_overflow(ops.intLeft, ops.intRight, &result))
+      this->throwError(errInfoIdx, "Integer overflow in subtraction");
+    return result;
+  } else if (ops.kind == Operands::Float) {
+    return ops.floatLeft - ops.floatRight;
   }
 
   std::stringstream ss;
   ss << "Cannot subtract " << right.toDbgString() << " from "
      << left.toDbgString();
   this->throwError(errInfoIdx, ss.str());
-
-  // Won't run
-  return left;
 }
 
 Value VM::performMul(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<std::int64_t>(left.value)) {
-    auto l = std::get<std::int64_t>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      return l * r;
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      return static_cast<double>(l) * r;
-    }
-  } else if (std::holds_alternative<double>(left.value)) {
-    auto l = std::get<double>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      return l * static_cast<double>(r);
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      return l * r;
+  auto ops = coerceOperands(left, right);
+  if (ops.kind == Operands::Int) {
+    std::int64_t result;
+    if (__builtin_mul_overflow(ops.intLeft, ops.intRight, &result))
+      this->throwError(errInfoIdx, "Integer overflow in multiplication");
+    return result;
+  } else if (ops.kind == Operands::Float) {
+    return ops.floatLeft * ops.floatRight;
+  }
+
+  // A string times an integer repeats the string
+  auto str = asString(left);
+  if (str && std::holds_alternative<std::int64_t>(right.value)) {
+    auto count = std::get<std::int64_t>(right.value);
+    if (count < 0) {
+      std::stringstream ss;
+      ss << "Cannot repeat a string " << count << " times";
+      this->throwError(errInfoIdx, ss.str());
     }
+
+    std::string result;
+    result.reserve(str->value.size() * count);
+    for (std::int64_t i = 0; i < count; i++) result += str->value;
+    return this->gc.createString(result);
   }
 
   std::stringstream ss;
-  ss << "Cannot multipy " << left.toDbgString() << " by "
+  ss << "Cannot multiply " << left.toDbgString() << " by "
      << right.toDbgString();
   this->throwError(errInfoIdx, ss.str());
-
-  // Won't run
-  return left;
 }
 
 Value VM::performDiv(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<std::int64_t>(left.value)) {
-    auto l = std::get<std::int64_t>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      if (r == 0) this->throwError(errInfoIdx, "Cannot divide by zero");
-      return l / r;
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      if (r == 0.0) this->throwError(errInfoIdx, "Cannot divide by zero");
-      return static_cast<double>(l) / r;
-    }
-  } else if (std::holds_alternative<double>(left.value)) {
-    auto l = std::get<double>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      if (r == 0) this->throwError(errInfoIdx, "Cannot divide by zero");
-      return l / static_cast<double>(r);
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      if (r == 0.0) this->throwError(errInfoIdx, "Cannot divide by zero");
-      return l / r;
-    }
+  auto ops = coerceOperands(left, right);
+  // Only integer division by zero is an error; floats follow IEEE 754 and
+  // give inf or nan
+  if (ops.kind == Operands::Int) {
+    if (ops.intRight == 0)
+      this->throwError(errInfoIdx, "Cannot divide by zero");
+    if ((ops.intLeft == INT64_MIN) && (ops.intRight == -1))
+      this->throwError(errInfoIdx, "Integer overflow in division");
+    return ops.intLeft / ops.intRight;
+  } else if (ops.kind == Operands::Float) {
+    return ops.floatLeft / ops.floatRight;
   }
 
   std::stringstream ss;
   ss << "Cannot divide " << left.toDbgString() << " by " << right.toDbgString();
   this->throwError(errInfoIdx, ss.str());
-
-  // Won't run
-  return left;
 }
 
 Value VM::performMod(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<std::int64_t>(left.value)) {
-    auto l = std::get<std::int64_t>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      if (r == 0) this->throwError(errInfoIdx, "Cannot mod by 0");
-      return l % r;
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      if (r == 0.0) this->throwError(errInfoIdx, "Cannot mod by 0");
-      return fmod(static_cast<double>(l), r);
-    }
-  } else if (std::holds_alternative<double>(left.value)) {
-    auto l = std::get<double>(left.value);
-    if (std::holds_alternative<std::int64_t>(right.value)) {
-      auto r = std::get<std::int64_t>(right.value);
-      if (r == 0) this->throwError(errInfoIdx, "Cannot mod by 0");
-      return fmod(l, static_cast<double>(r));
-    } else if (std::holds_alternative<double>(right.value)) {
-      auto r = std::get<double>(right.value);
-      if (r == 0.0) this->throwError(errInfoIdx, "Cannot mod by 0");
-      return fmod(l, r);
-    }
+  auto ops = coerceOperands(left, right);
+  if (ops.kind == Operands::Int) {
+    if (ops.intRight == 0) this->throwError(errInfoIdx, "Cannot mod by 0");
+    // INT64_MIN % -1 traps on x86 even though the result is 0
+    if (ops.intRight == -1) return static_cast<std::int64_t>(0);
+    return ops.intLeft % ops.intRight;
+  } else if (ops.kind == Operands::Float) {
+    return fmod(ops.floatLeft, ops.floatRight);
   }
 
   std::stringstream ss;
   ss << "Cannot mod with " << left.toDbgString() << " and "
      << right.toDbgString();
   this->throwError(errInfoIdx, ss.str());
+}
 
-  // Won't run
-  return left;
+// Lists can contain themselves, so looking into collections is bounded
+const auto MAX_EQUALITY_DEPTH = 64;
+
+// Decimals with trailing zeros are equal to their shorter forms, so both are
+// reduced rather than rescaled, which could overflow
+static bool decimalsEqual(Decimal* a, Decimal* b) {
+  auto reduce = [](Decimal* d) {
+    auto units = d->units;
+    auto scale = d->scale;
+    while (scale > 0 && units % 10 == 0) {
+      units /= 10;
+      scale--;
+    }
+    return std::make_pair(units, scale);
+  };
+  return reduce(a) == reduce(b);
 }
 
-Value VM::performEq(std::uint16_t errInfoIdx) {
-  auto right = this->pop();
-  auto left = this->pop();
+// `_` only equals itself, numbers compare by value whether integer or float,
+// and collections compare element by element. Values of different types are
+// never equal, and functions and errors are equal only to themselves.
+bool VM::valuesEqual(Value& left,
+                     Value& right,
+                     std::uint16_t errInfoIdx,
+                     int depth) {
+  if (depth == MAX_EQUALITY_DEPTH)
+    this->throwError(errInfoIdx, "Values are nested too deeply to compare");
 
   if (std::holds_alternative<char>(left.value)) {
-    return true;
+    return std::holds_alternative<char>(right.value);
   } else if (std::holds_alternative<std::int64_t>(left.value)) {
     auto l = std::get<std::int64_t>(left.value);
     if (std::holds_alternative<std::int64_t>(right.value)) {
@@ -787,48 +1288,76 @@ Value VM::performEq(std::uint16_t errInfoIdx) {
       auto r = std::get<bool>(right.value);
       return l == r;
     }
-  } else if (std::holds_alternative<Object*>(left.value)) {
+  } else if (std::holds_alternative<Object*>(left.value) &&
+             std::holds_alternative<Object*>(right.value)) {
     auto leftObj = std::get<Object*>(left.value);
-    if (typeid(leftObj) == typeid(String)) {
-      auto l = static_cast<String*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(String)) {
-          auto r = static_cast<String*>(rightObj);
-          return l->value == r->value;
-        }
-      }
-    } else if (typeid(leftObj) == typeid(Atom)) {
-      auto l = static_cast<Atom*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(Atom)) {
-          auto r = static_cast<Atom*>(rightObj);
-          return l->value == r->value;
-        }
+    auto rightObj = std::get<Object*>(right.value);
+    // Interned atoms are equal exactly when they are the same object
+    if (leftObj == rightObj) return true;
+    if (typeid(*leftObj) != typeid(*rightObj)) return false;
+
+    if (typeid(*leftObj) == typeid(String)) {
+      return static_cast<String*>(leftObj)->value ==
+             static_cast<String*>(rightObj)->value;
+    } else if (typeid(*leftObj) == typeid(Atom)) {
+      return static_cast<Atom*>(leftObj)->value ==
+             static_cast<Atom*>(rightObj)->value;
+    } else if (typeid(*leftObj) == typeid(Decimal)) {
+      return decimalsEqual(static_cast<Decimal*>(leftObj),
+                           static_cast<Decimal*>(rightObj));
+    } else if (typeid(*leftObj) == typeid(List) ||
+               typeid(*leftObj) == typeid(Tuple)) {
+      auto& l = typeid(*leftObj) == typeid(List)
+                    ? static_cast<List*>(leftObj)->elements
+                    : static_cast<Tuple*>(leftObj)->values;
+      auto& r = typeid(*rightObj) == typeid(List)
+                    ? static_cast<List*>(rightObj)->elements
+                    : static_cast<Tuple*>(rightObj)->values;
+      if (l.size() != r.size()) return false;
+      for (std::size_t i = 0; i < l.size(); i++)
+        if (!this->valuesEqual(l[i], r[i], errInfoIdx, depth + 1))
+          return false;
+      return true;
+    } else if (typeid(*leftObj) == typeid(Table)) {
+      // Tables are equal regardless of the order their keys were added in
+      auto& l = static_cast<Table*>(leftObj)->hashMap;
+      auto& r = static_cast<Table*>(rightObj)->hashMap;
+      if (l.size() != r.size()) return false;
+      for (auto& pair : l) {
+        auto found = r.find(pair.first);
+        if (found == r.end() ||
+            !this->valuesEqual(pair.second, found->second, errInfoIdx,
+                               depth + 1))
+          return false;
       }
+      return true;
     }
   }
 
-  std::stringstream ss;
-  ss << "Cannot compare " << left.toDbgString() << " and "
-     << right.toDbgString();
-  this->throwError(errInfoIdx, ss.str());
+  return false;
+}
 
-  return left;
+Value VM::performEq(std::uint16_t errInfoIdx) {
+  auto right = this->pop();
+  auto left = this->pop();
+  return this->valuesEqual(left, right, errInfoIdx, 0);
 }
 
 Value VM::performNEq(std::uint16_t errInfoIdx) {
   return !std::get<bool>(this->performEq(errInfoIdx).value);
 }
 
+const std::string* flan::orderedText(Object* obj) {
+  if (typeid(*obj) == typeid(String)) return &static_cast<String*>(obj)->value;
+  if (typeid(*obj) == typeid(Atom)) return &static_cast<Atom*>(obj)->value;
+  return nullptr;
+}
+
 Value VM::performLT(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<char>(left.value)) {
-    return true;
-  } else if (std::holds_alternative<std::int64_t>(left.value)) {
+  if (std::holds_alternative<std::int64_t>(left.value)) {
     auto l = std::get<std::int64_t>(left.value);
     if (std::holds_alternative<std::int64_t>(right.value)) {
       auto r = std::get<std::int64_t>(right.value);
@@ -846,18 +1375,13 @@ Value VM::performLT(std::uint16_t errInfoIdx) {
       auto r = std::get<double>(right.value);
       return l < r;
     }
-  } else if (std::holds_alternative<Object*>(left.value)) {
+  } else if (std::holds_alternative<Object*>(left.value) &&
+             std::holds_alternative<Object*>(right.value)) {
     auto leftObj = std::get<Object*>(left.value);
-    if (typeid(leftObj) == typeid(String)) {
-      auto l = static_cast<String*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(String)) {
-          auto r = static_cast<String*>(rightObj);
-          return l->value < r->value;
-        }
-      }
-    }
+    auto rightObj = std::get<Object*>(right.value);
+    auto l = orderedText(leftObj);
+    if (l != nullptr && typeid(*leftObj) == typeid(*rightObj))
+      return *l < *orderedText(rightObj);
   }
 
   std::stringstream ss;
@@ -872,9 +1396,7 @@ Value VM::performLTE(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<char>(left.value)) {
-    return true;
-  } else if (std::holds_alternative<std::int64_t>(left.value)) {
+  if (std::holds_alternative<std::int64_t>(left.value)) {
     auto l = std::get<std::int64_t>(left.value);
     if (std::holds_alternative<std::int64_t>(right.value)) {
       auto r = std::get<std::int64_t>(right.value);
@@ -892,18 +1414,13 @@ Value VM::performLTE(std::uint16_t errInfoIdx) {
       auto r = std::get<double>(right.value);
       return l <= r;
     }
-  } else if (std::holds_alternative<Object*>(left.value)) {
+  } else if (std::holds_alternative<Object*>(left.value) &&
+             std::holds_alternative<Object*>(right.value)) {
     auto leftObj = std::get<Object*>(left.value);
-    if (typeid(leftObj) == typeid(String)) {
-      auto l = static_cast<String*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(String)) {
-          auto r = static_cast<String*>(rightObj);
-          return l->value <= r->value;
-        }
-      }
-    }
+    auto rightObj = std::get<Object*>(right.value);
+    auto l = orderedText(leftObj);
+    if (l != nullptr && typeid(*leftObj) == typeid(*rightObj))
+      return *l <= *orderedText(rightObj);
   }
 
   std::stringstream ss;
@@ -918,9 +1435,7 @@ Value VM::performGT(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<char>(left.value)) {
-    return true;
-  } else if (std::holds_alternative<std::int64_t>(left.value)) {
+  if (std::holds_alternative<std::int64_t>(left.value)) {
     auto l = std::get<std::int64_t>(left.value);
     if (std::holds_alternative<std::int64_t>(right.value)) {
       auto r = std::get<std::int64_t>(right.value);
@@ -938,18 +1453,13 @@ Value VM::performGT(std::uint16_t errInfoIdx) {
       auto r = std::get<double>(right.value);
       return l > r;
     }
-  } else if (std::holds_alternative<Object*>(left.value)) {
+  } else if (std::holds_alternative<Object*>(left.value) &&
+             std::holds_alternative<Object*>(right.value)) {
     auto leftObj = std::get<Object*>(left.value);
-    if (typeid(leftObj) == typeid(String)) {
-      auto l = static_cast<String*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(String)) {
-          auto r = static_cast<String*>(rightObj);
-          return l->value > r->value;
-        }
-      }
-    }
+    auto rightObj = std::get<Object*>(right.value);
+    auto l = orderedText(leftObj);
+    if (l != nullptr && typeid(*leftObj) == typeid(*rightObj))
+      return *l > *orderedText(rightObj);
   }
 
   std::stringstream ss;
@@ -964,9 +1474,7 @@ Value VM::performGTE(std::uint16_t errInfoIdx) {
   auto right = this->pop();
   auto left = this->pop();
 
-  if (std::holds_alternative<char>(left.value)) {
-    return true;
-  } else if (std::holds_alternative<std::int64_t>(left.value)) {
+  if (std::holds_alternative<std::int64_t>(left.value)) {
     auto l = std::get<std::int64_t>(left.value);
     if (std::holds_alternative<std::int64_t>(right.value)) {
       auto r = std::get<std::int64_t>(right.value);
@@ -984,18 +1492,13 @@ Value VM::performGTE(std::uint16_t errInfoIdx) {
       auto r = std::get<double>(right.value);
       return l >= r;
     }
-  } else if (std::holds_alternative<Object*>(left.value)) {
+  } else if (std::holds_alternative<Object*>(left.value) &&
+             std::holds_alternative<Object*>(right.value)) {
     auto leftObj = std::get<Object*>(left.value);
-    if (typeid(leftObj) == typeid(String)) {
-      auto l = static_cast<String*>(leftObj);
-      if (std::holds_alternative<Object*>(right.value)) {
-        auto rightObj = std::get<Object*>(right.value);
-        if (typeid(rightObj) == typeid(String)) {
-          auto r = static_cast<String*>(rightObj);
-          return l->value >= r->value;
-        }
-      }
-    }
+    auto rightObj = std::get<Object*>(right.value);
+    auto l = orderedText(leftObj);
+    if (l != nullptr && typeid(*leftObj) == typeid(*rightObj))
+      return *l >= *orderedText(rightObj);
   }
 
   std::stringstream ss;
@@ -1018,41 +1521,41 @@ Value VM::performOr() {
   return left.truthy() || right.truthy();
 }
 
-bool VM::checkMagicNumber(std::uint8_t* bufferPtr) {
-  return (this->readUInt8(bufferPtr) == MAGIC_NUMBER[0]) &&
-         (this->readUInt8(bufferPtr) == MAGIC_NUMBER[1]) &&
-         (this->readUInt8(bufferPtr) == MAGIC_NUMBER[2]) &&
-         (this->readUInt8(bufferPtr) == MAGIC_NUMBER[3]);
+bool VM::checkMagicNumber(Cursor& cursor) {
+  return (this->readUInt8(cursor) == MAGIC_NUMBER[0]) &&
+         (this->readUInt8(cursor) == MAGIC_NUMBER[1]) &&
+         (this->readUInt8(cursor) == MAGIC_NUMBER[2]) &&
+         (this->readUInt8(cursor) == MAGIC_NUMBER[3]);
 }
 
-bool VM::checkVersion(std::uint8_t* bufferPtr) {
-  return (this->readUInt8(bufferPtr) == VERSION[0]) &&
-         (this->readUInt8(bufferPtr) <= VERSION[1]) &&
-         (this->readUInt8(bufferPtr) <= VERSION[2]);
+void VM::checkVersion(Cursor& cursor) {
+  std::uint8_t version[3];
+  for (auto& part : version) part = this->readUInt8(cursor);
+  auto error = incompatibility(version, this->readUInt8(cursor));
+  if (!error.empty()) this->throwError(error);
 }
 
-std::uint8_t VM::readUInt8(std::uint8_t* bufferPtr) {
-  std::uint8_t value = *bufferPtr;
-  bufferPtr++;
-  return value;
+std::uint8_t VM::readUInt8(Cursor& cursor) {
+  if (cursor.ip == cursor.end) this->throwError("Unexpected end of bytecode");
+  return *cursor.ip++;
 }
 
-std::uint16_t VM::readUInt16(std::uint8_t* bufferPtr) {
-  auto low_byte = this->readUInt8(bufferPtr);
-  auto high_byte = this->readUInt8(bufferPtr);
+std::uint16_t VM::readUInt16(Cursor& cursor) {
+  auto low_byte = this->readUInt8(cursor);
+  auto high_byte = this->readUInt8(cursor);
   return static_cast<std::uint16_t>(low_byte) |
          (static_cast<std::uint16_t>(high_byte) << 8);
 }
 
-std::uint32_t VM::readUInt32(std::uint8_t* bufferPtr) {
-  auto byte1 = this->readUInt8(bufferPtr);
-  auto byte2 = this->readUInt8(bufferPtr);
-  auto byte3 = this->readUInt8(bufferPtr);
-  auto byte4 = this->readUInt8(bufferPtr);
+std::uint32_t VM::readUInt32(Cursor& cursor) {
+  auto byte1 = this->readUInt8(cursor);
+  auto byte2 = this->readUInt8(cursor);
+  auto byte3 = this->readUInt8(cursor);
+  auto byte4 = this->readUInt8(cursor);
   return static_cast<std::uint32_t>(byte1) |
-         (static_cast<std::uint32_t>(byte2)) |
-         (static_cast<std::uint32_t>(byte3)) |
-         (static_cast<std::uint32_t>(byte4));
+         (static_cast<std::uint32_t>(byte2) << 8) |
+         (static_cast<std::uint32_t>(byte3) << 16) |
+         (static_cast<std::uint32_t>(byte4) << 24);
 }
 
 void VM::push(Value value) {
@@ -1063,42 +1566,54 @@ Value VM::pop() {
   return this->stack.pop();
 }
 
-void VM::jumpForward(std::uint8_t* bufferPtr, std::size_t offset) {
-  bufferPtr += offset;
+std::vector<Value> VM::popN(std::size_t count) {
+  auto& values = this->stack.stack;
+  std::vector<Value> popped(values.end() - count, values.end());
+  values.resize(values.size() - count);
+  return popped;
+}
+
+void VM::jumpForward(Cursor& cursor, std::size_t offset) {
+  if (static_cast<std::size_t>(cursor.end - cursor.ip) <= offset)
+    this->throwError("Jump out of bytecode range");
+  cursor.ip += offset;
 }
 
-std::string VM::readShortString(std::uint8_t* bufferPtr) {
-  auto length = this->readUInt8(bufferPtr);
+std::string VM::readShortString(Cursor& cursor) {
+  auto length = this->readUInt8(cursor);
   std::string str;
   str.reserve(length);
   for (std::uint32_t i = 0; i < length; i++)
-    str += static_cast<char>(this->readUInt8(bufferPtr));
+    str += static_cast<char>(this->readUInt8(cursor));
   return str;
 }
 
-Value VM::readValue(std::uint8_t* bufferPtr) {
-  auto type = this->readUInt8(bufferPtr);
-  bufferPtr++;
+Value VM::readValue(Cursor& cursor) {
+  auto type = this->readUInt8(cursor);
 
   switch (type) {
     case 0:
-      return this->readInteger(bufferPtr);
+      return this->readInteger(cursor);
     case 1:
-      return this->readFloat(bufferPtr);
+      return this->readFloat(cursor);
     case 2:
-      return this->readBool(bufferPtr);
+      return this->readBool(cursor);
     case 3:
       return this->readEmpty();
     case 4:
-      return readString(bufferPtr);
+      return readString(cursor, false);
     case 5:
-      return readAtom(bufferPtr);
+      return readAtom(cursor);
     case 6:
-      return readFunction(bufferPtr);
+      return readFunction(cursor);
+    case 7:
+      return this->readLongInteger(cursor);
+    case 8:
+      return readString(cursor, true);
     default: {
       std::stringstream ss;
       ss << "Invalid value type " << std::hex << std::setw(2)
-         << std::setfill('0') << type;
+         << std::setfill('0') << static_cast<int>(type);
       this->throwError(ss.str());
     }
   }
@@ -1107,9 +1622,9 @@ Value VM::readValue(std::uint8_t* bufferPtr) {
   return Value();
 }
 
-Value VM::readInteger(std::uint8_t* bufferPtr) {
+Value VM::readInteger(Cursor& cursor) {
   std::uint8_t bytes[4];
-  for (auto i = 0; i < 4; i++) bytes[i] = this->readUInt8(bufferPtr);
+  for (auto i = 0; i < 4; i++) bytes[i] = this->readUInt8(cursor);
 
   std::int64_t result = 0;
   for (auto i = 0; i < 4; i++)
@@ -1118,90 +1633,300 @@ Value VM::readInteger(std::uint8_t* bufferPtr) {
   return result;
 }
 
-Value VM::readFloat(std::uint8_t* bufferPtr) {
-  std::uint8_t bytes[4];
-  for (auto i = 0; i < 4; i++) bytes[i] = this->readUInt8(bufferPtr);
+// The 4-byte form is unsigned, so negative and larger integers are written
+// out in full
+Value VM::readLongInteger(Cursor& cursor) {
+  std::uint64_t result = 0;
+  for (auto i = 0; i < 8; i++)
+    result |= static_cast<std::uint64_t>(this->readUInt8(cursor)) << (i * 8);
+  return static_cast<std::int64_t>(result);
+}
+
+Value VM::readFloat(Cursor& cursor) {
+  std::uint8_t bytes[8];
+  for (auto i = 0; i < 8; i++) bytes[i] = this->readUInt8(cursor);
 
   double result = 0.0;
-  std::memcpy(&result, bytes, 4);
+  std::memcpy(&result, bytes, 8);
 
   return result;
 }
 
-Value VM::readBool(std::uint8_t* bufferPtr) {
-  return this->readUInt8(bufferPtr) == 1;
+Value VM::readBool(Cursor& cursor) {
+  return this->readUInt8(cursor) == 1;
 }
 
 Value VM::readEmpty() {
   return Value();
 }
 
-Value VM::readString(std::uint8_t* bufferPtr) {
-  auto length = this->readUInt16(bufferPtr);
+Value VM::readString(Cursor& cursor, bool isLong) {
+  std::uint32_t length =
+      isLong ? this->readUInt32(cursor) : this->readUInt16(cursor);
   std::string s;
   s.reserve(length);
-  for (auto i = 0; i < length; i++)
-    s += static_cast<char>(this->readUInt8(bufferPtr));
+  for (std::uint32_t i = 0; i < length; i++)
+    s += static_cast<char>(this->readUInt8(cursor));
   return this->gc.createString(s);
 }
 
-Value VM::readAtom(std::uint8_t* bufferPtr) {
-  auto length = this->readUInt8(bufferPtr);
+// Atom constants are interned, and each site in the bytecode remembers the
+// atom it resolved to, so a match over atoms hashes each name only once
+Value VM::readAtom(Cursor& cursor) {
+  auto site = cursor.ip;
+  auto cached = this->atomSites.find(site);
+  if (cached != this->atomSites.end()) {
+    this->atomStats.hits++;
+    cursor.ip += 1 + *site;
+    return cached->second;
+  }
+
+  this->atomStats.misses++;
+  auto length = this->readUInt8(cursor);
   std::string s;
   s.reserve(length);
   for (auto i = 0; i < length; i++)
-    s += static_cast<char>(this->readUInt8(bufferPtr));
-  return this->gc.createAtom(s);
+    s += static_cast<char>(this->readUInt8(cursor));
+
+  auto interned = this->atoms.find(s);
+  if (interned == this->atoms.end()) {
+    auto atom = this->gc.createAtom(s);
+    this->constants.push_back(atom);
+    interned = this->atoms.insert({s, atom}).first;
+  }
+  this->atomSites.insert({site, interned->second});
+  return interned->second;
 }
 
-Value VM::readFunction(std::uint8_t* bufferPtr) {
-  auto funcName = this->readShortString(bufferPtr);
-  auto arity = this->readUInt16(bufferPtr);
-  auto funcBuffers = this->readFunctionBody(bufferPtr);
-  return this->gc.createFunction(funcName, arity, funcBuffers);
+Upvalue* VM::captureUpvalue(std::size_t slot) {
+  for (auto& value : this->openUpvalues) {
+    auto upvalue = static_cast<Upvalue*>(std::get<Object*>(value.value));
+    if (upvalue->slot == slot) return upvalue;
+  }
+
+  auto upvalue = this->gc.createUpvalue(slot);
+  this->openUpvalues.push_back(upvalue);
+  return static_cast<Upvalue*>(std::get<Object*>(upvalue.value));
 }
 
-std::uint8_t* VM::readFunctionBody(std::uint8_t* bufferPtr) {
-  auto length = std::get<std::int64_t>(this->readInteger(bufferPtr).value);
-  auto buffers = new std::uint8_t[length];
-  for (auto i = 0; i < length; i++) buffers[i] = this->readUInt8(bufferPtr);
+Value& VM::upvalueRef(Upvalue* upvalue) {
+  return upvalue->open ? this->stack.stack[upvalue->slot] : upvalue->closed;
+}
 
-  auto endFn = this->readUInt8(bufferPtr);
+// Moves the value of every open upvalue at or above `from` out of the stack,
+// before those slots are dropped
+void VM::closeUpvalues(std::size_t from) {
+  auto& open = this->openUpvalues;
+  for (auto it = open.begin(); it != open.end();) {
+    auto upvalue = static_cast<Upvalue*>(std::get<Object*>(it->value));
+    if (upvalue->slot < from) {
+      it++;
+      continue;
+    }
+    upvalue->closed = this->stack.stack[upvalue->slot];
+    upvalue->open = false;
+    it = open.erase(it);
+  }
+}
+
+Value VM::readFunction(Cursor& cursor) {
+  auto funcName = this->readShortString(cursor);
+  auto arity = this->readUInt16(cursor);
+  auto length = this->readUInt32(cursor);
+  auto chunk = this->readFunctionBody(cursor, length);
+  return this->gc.createFunction(funcName, arity, chunk);
+}
+
+Chunk VM::readFunctionBody(Cursor& cursor, std::uint32_t length) {
+  if (static_cast<std::size_t>(cursor.end - cursor.ip) < length)
+    this->throwError("Unexpected end of bytecode");
+
+  auto chunk = Chunk{cursor.ip, length};
+  cursor.ip += length;
+
+  auto endFn = this->readUInt8(cursor);
   if (InstructionType::EndFn != static_cast<InstructionType>(endFn)) {
     std::stringstream ss;
     ss << "Expected " << std::hex << std::setw(2) << std::setfill('0')
-       << static_cast<std::uint8_t>(InstructionType::EndFn) << " but got "
-       << std::hex << std::setw(2) << std::setfill('0') << endFn;
+       << static_cast<int>(InstructionType::EndFn) << " but got " << std::hex
+       << std::setw(2) << std::setfill('0') << static_cast<int>(endFn);
     this->throwError(ss.str());
   }
 
-  return buffers;
+  return chunk;
 }
 
-void VM::throwError(std::uint16_t errInfoIdx, std::string msg) {
-  delete[] this->buffer;
+GC& VM::getGC() {
+  return this->gc;
+}
+
+std::vector<std::string> VM::globalNames() {
+  std::vector<std::string> names;
+  for (SymbolId id = 0; id < this->globalKinds.size(); id++)
+    if (this->globalKinds[id] != GlobalKind::Undefined)
+      names.push_back(this->symbols.name(id));
+  std::sort(names.begin(), names.end());
+  return names;
+}
 
-  std::cerr << "Stack trace:\n";
+bool VM::userGlobal(const std::string& name, Value& value) {
+  SymbolId id;
+  if (!this->symbols.find(name, id) ||
+      this->globalKinds[id] != GlobalKind::User)
+    return false;
+  value = this->globals[id];
+  return true;
+}
 
-  // TODO: Maybe fix this later?
-  for (int i = this->callframes.size() - 1; i >= 0; i--) {
-    auto frame = this->callframes[i];
-    std::cerr << frame.function->name << "\n";
+const AtomStats& VM::getAtomStats() {
+  return this->atomStats;
+}
+
+void VM::defineGlobal(std::string name, Value value) {
+  auto id = this->globalSlot(name);
+  this->globals[id] = value;
+  this->globalKinds[id] = GlobalKind::Builtin;
+}
+
+void VM::defineLazyGlobal(std::string name, LazyInit init) {
+  auto id = this->globalSlot(name);
+  this->globalKinds[id] = GlobalKind::Lazy;
+  this->lazyInits[id] = init;
+}
+
+// Interns a name and makes sure it has a slot, which starts out undefined
+SymbolId VM::globalSlot(const std::string& name) {
+  auto id = this->symbols.intern(name);
+  if (id == this->globals.size()) {
+    this->globals.emplace_back();
+    this->globalKinds.push_back(GlobalKind::Undefined);
+  }
+  return id;
+}
+
+void VM::nativeError(std::string msg) {
+  this->throwError(this->nativeErrInfoIdx, msg);
+}
+
+Value VM::call(Value callee, std::vector<Value>& args) {
+  this->push(callee);
+  for (auto& arg : args) this->push(arg);
+
+  // Execution stops as soon as the callee's frame is popped, so nothing ever
+  // reads through the empty cursor it returns to
+  auto errInfoIdx = this->nativeErrInfoIdx;
+  auto returnDepth = this->callframes.size();
+  auto paused = this->gc.liftPause();
+  auto cursor = Cursor(nullptr, 0);
+  this->callFunc(cursor, callee, static_cast<std::uint16_t>(args.size()),
+                 errInfoIdx);
+  if (this->callframes.size() > returnDepth)
+    this->execute(cursor, returnDepth);
+
+  this->gc.restorePause(paused);
+  this->nativeErrInfoIdx = errInfoIdx;
+  return this->pop();
+}
+
+void VM::root(Value value) {
+  this->push(value);
+}
+
+std::vector<Value>& VM::nativeData() {
+  return this->currentNative->data;
+}
+
+void VM::writePostMortem(ErrorInfo& errInfo, std::string& msg) {
+  auto dump = std::ofstream(this->options.postMortemPath);
+  if (!dump.is_open()) {
+    std::cerr << "Failed to write post-mortem dump "
+              << this->options.postMortemPath << "\n";
+    return;
   }
 
-  std::cerr << "\n";
+  dump << "Error at line " << errInfo.line << ": " << msg << "\n";
+  dump << "  " << errInfo.lineText << "\n\n";
+
+  dump << "Call frames (innermost first):\n";
+  for (int i = this->callframes.size() - 1; i >= 0; i--)
+    dump << "  " << this->callframes[i].function->name << "\n";
+  dump << "  <top level>\n\n";
+
+  dump << "Stack (bottom first):\n";
+  auto stackValues = this->stack.actualStack();
+  for (std::size_t i = 0; i < stackValues->size(); i++)
+    dump << "  [" << i << "] " << stackValues->at(i).toDbgString() << "\n";
+  dump << "\n";
+
+  std::vector<SymbolId> ids;
+  for (SymbolId id = 0; id < this->symbols.size(); id++)
+    if (this->globalKinds[id] == GlobalKind::User) ids.push_back(id);
+  std::sort(ids.begin(), ids.end(), [&](SymbolId a, SymbolId b) {
+    return this->symbols.name(a) < this->symbols.name(b);
+  });
+
+  dump << "Globals:\n";
+  for (auto id : ids)
+    dump << "  " << this->symbols.name(id) << " = "
+         << this->globals[id].toDbgString() << "\n";
+}
+
+std::vector<std::string> VM::stackTrace() {
+  std::vector<std::string> trace;
+  for (auto i = this->callframes.size(); i-- > 0;)
+    trace.push_back(this->callframes[i].function->name);
+  return trace;
+}
 
+void VM::throwError(std::uint16_t errInfoIdx, std::string msg) {
   ErrorInfo errInfo = this->errorInfoList.at(errInfoIdx);
-  std::cerr << errInfo.lineText << "\n";
-  std::cerr << "Error at line " << errInfo.line << ":" << msg << std::endl;
-  std::exit(1);
+  if (!this->options.postMortemPath.empty())
+    this->writePostMortem(errInfo, msg);
+
+  throw RuntimeError(msg, this->fileName, errInfo.line, errInfo.lineText,
+                     this->stackTrace());
 }
 
 void VM::throwError(std::string msg) {
   delete[] this->buffer;
 
   std::cerr << "Error: " << msg << std::endl;
-  std::exit(1);
+  std::exit(EXIT_LOAD_ERROR);
+}
+
+void VM::throwRuntimeError(std::string msg) {
+  throw RuntimeError(msg, this->fileName, 0, "", this->stackTrace());
+}
+
+RuntimeError::RuntimeError(const std::string& msg,
+                           fs::path fileName,
+                           std::uint16_t line,
+                           std::string lineText,
+                           std::vector<std::string> trace)
+    : std::runtime_error(msg),
+      fileName{fileName},
+      line{line},
+      lineText{lineText},
+      trace{trace} {}
+
+void RuntimeError::report(std::ostream& out) const {
+  if (!this->trace.empty()) {
+    out << "Stack trace:\n";
+    for (std::size_t i = 0; i < this->trace.size(); i++) {
+      if (i == TRACE_FRAMES_SHOWN) {
+        out << "... " << this->trace.size() - i << " more frames\n";
+        break;
+      }
+      out << "  " << this->trace[i] << "\n";
+    }
+    out << "\n";
+  }
+
+  out << this->fileName.string();
+  if (this->line != 0) out << ":" << this->line;
+  out << ": " << this->what() << "\n";
+  if (!this->lineText.empty()) out << "    " << this->lineText << "\n";
+  out.flush();
 }
 
 Stack::Stack() {